@@ -0,0 +1,3 @@
+DROP TABLE study_phase_prerequisites;
+DROP TABLE study_phases;
+DROP TABLE study_protocols;
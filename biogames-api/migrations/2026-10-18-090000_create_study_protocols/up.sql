@@ -0,0 +1,60 @@
+-- The study design, previously hardcoded in create_game. Exactly one protocol
+-- is active at a time; phases are the game types a participant may play.
+
+CREATE TABLE study_protocols (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    description TEXT,
+    active BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+
+CREATE UNIQUE INDEX study_protocols_single_active ON study_protocols (active) WHERE active;
+
+CREATE TABLE study_phases (
+    id SERIAL PRIMARY KEY,
+    protocol_id INTEGER NOT NULL REFERENCES study_protocols(id) ON DELETE CASCADE,
+    game_type VARCHAR NOT NULL,
+    position INTEGER NOT NULL,
+    -- NULL = unlimited
+    max_attempts INTEGER CHECK (max_attempts IS NULL OR max_attempts > 0),
+    challenges_per_game INTEGER NOT NULL CHECK (challenges_per_game > 0),
+    core_set TEXT NOT NULL CHECK (core_set IN ('held_out_test', 'training_pool')),
+    UNIQUE (protocol_id, game_type),
+    UNIQUE (protocol_id, position)
+);
+
+-- A phase may start once the user has played at least `min_games` games of
+-- each listed type.
+CREATE TABLE study_phase_prerequisites (
+    id SERIAL PRIMARY KEY,
+    phase_id INTEGER NOT NULL REFERENCES study_phases(id) ON DELETE CASCADE,
+    required_game_type VARCHAR NOT NULL,
+    min_games INTEGER NOT NULL DEFAULT 1 CHECK (min_games > 0),
+    UNIQUE (phase_id, required_game_type)
+);
+
+-- Seed the design that was in the code: one pretest of 50 held-out cores, up
+-- to five trainings of 20, then one posttest of 50 after all five trainings.
+INSERT INTO study_protocols (name, description, active)
+VALUES ('default', 'Pretest, 5 trainings, posttest', TRUE);
+
+INSERT INTO study_phases (protocol_id, game_type, position, max_attempts, challenges_per_game, core_set)
+SELECT id, phase.game_type, phase.position, phase.max_attempts, phase.challenges_per_game, phase.core_set
+FROM study_protocols,
+     (VALUES ('pretest', 1, 1, 50, 'held_out_test'),
+             ('training', 2, 5, 20, 'training_pool'),
+             ('posttest', 3, 1, 50, 'held_out_test'))
+         AS phase (game_type, position, max_attempts, challenges_per_game, core_set)
+WHERE name = 'default';
+
+INSERT INTO study_phase_prerequisites (phase_id, required_game_type, min_games)
+SELECT study_phases.id, prerequisite.required_game_type, prerequisite.min_games
+FROM study_phases
+JOIN study_protocols ON study_protocols.id = study_phases.protocol_id
+JOIN (VALUES ('training', 'pretest', 1),
+             ('posttest', 'pretest', 1),
+             ('posttest', 'training', 5))
+         AS prerequisite (game_type, required_game_type, min_games)
+     ON prerequisite.game_type = study_phases.game_type
+WHERE study_protocols.name = 'default';
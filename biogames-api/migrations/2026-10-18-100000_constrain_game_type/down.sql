@@ -0,0 +1,3 @@
+ALTER TABLE study_phase_prerequisites DROP CONSTRAINT study_phase_prerequisites_required_game_type_check;
+ALTER TABLE study_phases DROP CONSTRAINT study_phases_game_type_check;
+ALTER TABLE games DROP CONSTRAINT games_game_type_check;
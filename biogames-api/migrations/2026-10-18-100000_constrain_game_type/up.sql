@@ -0,0 +1,34 @@
+-- games.game_type was free text, so any `mode` query parameter became a game
+-- type. Refuse to continue if such rows exist: they need a human decision
+-- (relabel or delete) before the constraint can hold.
+DO $$
+DECLARE
+    invalid TEXT;
+BEGIN
+    SELECT string_agg(format('%s (%s games, ids %s)', game_type, n, ids), '; ')
+    INTO invalid
+    FROM (
+        SELECT game_type, count(*) AS n, string_agg(id::TEXT, ',' ORDER BY id) AS ids
+        FROM games
+        WHERE game_type NOT IN ('pretest', 'training', 'posttest')
+        GROUP BY game_type
+    ) AS unknown;
+
+    IF invalid IS NOT NULL THEN
+        RAISE EXCEPTION 'games has rows with unknown game_type: %', invalid
+            USING HINT = 'Update them to pretest, training or posttest (or delete them) and rerun the migrations.';
+    END IF;
+END
+$$;
+
+ALTER TABLE games
+    ADD CONSTRAINT games_game_type_check
+    CHECK (game_type IN ('pretest', 'training', 'posttest'));
+
+ALTER TABLE study_phases
+    ADD CONSTRAINT study_phases_game_type_check
+    CHECK (game_type IN ('pretest', 'training', 'posttest'));
+
+ALTER TABLE study_phase_prerequisites
+    ADD CONSTRAINT study_phase_prerequisites_required_game_type_check
+    CHECK (required_game_type IN ('pretest', 'training', 'posttest'));
@@ -0,0 +1 @@
+DROP VIEW games_over_attempt_limit;
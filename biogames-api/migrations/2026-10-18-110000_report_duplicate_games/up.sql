@@ -0,0 +1,28 @@
+-- Game creation now takes a per-user advisory lock, so a user can no longer
+-- end up with more games of a type than the protocol allows. Games created
+-- before that (e.g. two pretests from a double click) are left alone, since
+-- they may hold real answers; this view lists them for manual review and the
+-- API logs its contents at startup.
+CREATE VIEW games_over_attempt_limit AS
+SELECT games.user_id,
+       games.game_type,
+       count(*) AS games,
+       study_phases.max_attempts,
+       array_agg(games.id ORDER BY games.id) AS game_ids
+FROM games
+JOIN study_phases ON study_phases.game_type = games.game_type
+JOIN study_protocols ON study_protocols.id = study_phases.protocol_id AND study_protocols.active
+WHERE study_phases.max_attempts IS NOT NULL
+GROUP BY games.user_id, games.game_type, study_phases.max_attempts
+HAVING count(*) > study_phases.max_attempts;
+
+DO $$
+DECLARE
+    duplicate RECORD;
+BEGIN
+    FOR duplicate IN SELECT * FROM games_over_attempt_limit LOOP
+        RAISE WARNING 'user % has % % games (limit %): %',
+            duplicate.user_id, duplicate.games, duplicate.game_type, duplicate.max_attempts, duplicate.game_ids;
+    END LOOP;
+END
+$$;
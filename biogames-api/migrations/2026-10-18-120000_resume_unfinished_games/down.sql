@@ -0,0 +1 @@
+ALTER TABLE study_phases DROP COLUMN resume_unfinished;
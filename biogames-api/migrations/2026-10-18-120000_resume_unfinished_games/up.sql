@@ -0,0 +1,3 @@
+-- Whether asking for a new game of this phase returns the user's unfinished
+-- one instead (e.g. after a page refresh) rather than starting another.
+ALTER TABLE study_phases ADD COLUMN resume_unfinished BOOLEAN NOT NULL DEFAULT TRUE;
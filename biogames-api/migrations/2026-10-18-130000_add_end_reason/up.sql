@@ -0,0 +1,15 @@
+-- Why a game stopped: every challenge answered, the player quit, or the
+-- sweeper finalized it after it sat idle. NULL while the game is open.
+ALTER TABLE games
+    ADD COLUMN end_reason VARCHAR
+    CHECK (end_reason IN ('completed', 'quit', 'abandoned'));
+
+-- Quitting deletes the unanswered challenges, so a finished game that still
+-- has its full challenge count (max_score is 5 points per challenge) was
+-- completed and anything shorter was quit.
+UPDATE games
+SET end_reason = CASE
+    WHEN (SELECT count(*) FROM challenges WHERE challenges.game_id = games.id) * 5 >= games.max_score THEN 'completed'
+    ELSE 'quit'
+END
+WHERE finished_at IS NOT NULL;
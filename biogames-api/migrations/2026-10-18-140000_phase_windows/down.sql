@@ -0,0 +1,8 @@
+ALTER TABLE study_phases
+    DROP CONSTRAINT study_phases_relative_window,
+    DROP CONSTRAINT study_phases_window_order,
+    DROP COLUMN open_for_days,
+    DROP COLUMN unlocks_after_days,
+    DROP COLUMN unlocks_after,
+    DROP COLUMN closes_at,
+    DROP COLUMN opens_at;
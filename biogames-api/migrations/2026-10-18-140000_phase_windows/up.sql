@@ -0,0 +1,27 @@
+-- Availability windows for study phases. A phase can be started only while
+-- both its absolute window and its relative window (if any) are open.
+--
+-- Absolute: opens_at / closes_at bound the phase for every participant, e.g.
+-- the study's open and close dates. Either may be NULL for no bound.
+--
+-- Relative: the phase unlocks `unlocks_after_days` days after the participant
+-- finished their first `unlocks_after` game, and closes `open_for_days` days
+-- after unlocking (never, if NULL).
+ALTER TABLE study_phases
+    ADD COLUMN opens_at TIMESTAMPTZ,
+    ADD COLUMN closes_at TIMESTAMPTZ,
+    ADD COLUMN unlocks_after VARCHAR CHECK (unlocks_after IN ('pretest', 'training', 'posttest')),
+    ADD COLUMN unlocks_after_days INTEGER CHECK (unlocks_after_days >= 0),
+    ADD COLUMN open_for_days INTEGER CHECK (open_for_days > 0),
+    ADD CONSTRAINT study_phases_window_order CHECK (opens_at IS NULL OR closes_at IS NULL OR opens_at < closes_at),
+    ADD CONSTRAINT study_phases_relative_window CHECK (
+        (unlocks_after IS NULL) = (unlocks_after_days IS NULL)
+        AND (open_for_days IS NULL OR unlocks_after IS NOT NULL)
+    );
+
+-- The information sheet promises a two-week practice period between pretest
+-- and posttest.
+UPDATE study_phases
+SET unlocks_after = 'pretest', unlocks_after_days = 14
+WHERE game_type = 'posttest'
+  AND protocol_id = (SELECT id FROM study_protocols WHERE name = 'default');
@@ -0,0 +1,3 @@
+ALTER TABLE challenges DROP COLUMN timed_out;
+ALTER TABLE games DROP COLUMN time_limit_secs;
+ALTER TABLE study_phases DROP COLUMN time_limit_secs;
@@ -0,0 +1,12 @@
+-- Seconds a participant has to answer each challenge of the phase, counted
+-- from when its core was first served. NULL means no limit. The client makes
+-- players wait 5 seconds before answering, so shorter limits can't be met.
+ALTER TABLE study_phases ADD COLUMN time_limit_secs INTEGER CHECK (time_limit_secs >= 5);
+
+-- Copied from the phase when the game is created, so editing the protocol
+-- doesn't change the rules of a game already being played.
+ALTER TABLE games ADD COLUMN time_limit_secs INTEGER CHECK (time_limit_secs >= 5);
+
+-- Submitted after the deadline; scored with the timeout penalty instead of
+-- the guess.
+ALTER TABLE challenges ADD COLUMN timed_out BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,29 @@
+DELETE FROM study_phase_prerequisites
+WHERE phase_id IN (SELECT id FROM study_phases WHERE game_type = 'review' OR core_set = 'review_mistakes');
+DELETE FROM study_phases WHERE game_type = 'review' OR core_set = 'review_mistakes';
+
+ALTER TABLE study_phases DROP CONSTRAINT study_phases_core_set_check;
+ALTER TABLE study_phases
+    ADD CONSTRAINT study_phases_core_set_check
+    CHECK (core_set IN ('held_out_test', 'training_pool'));
+
+ALTER TABLE study_phase_prerequisites DROP CONSTRAINT study_phase_prerequisites_required_game_type_check;
+ALTER TABLE study_phase_prerequisites
+    ADD CONSTRAINT study_phase_prerequisites_required_game_type_check
+    CHECK (required_game_type IN ('pretest', 'training', 'posttest'));
+
+ALTER TABLE study_phases DROP CONSTRAINT study_phases_unlocks_after_check;
+ALTER TABLE study_phases
+    ADD CONSTRAINT study_phases_unlocks_after_check
+    CHECK (unlocks_after IN ('pretest', 'training', 'posttest'));
+
+ALTER TABLE study_phases DROP CONSTRAINT study_phases_game_type_check;
+ALTER TABLE study_phases
+    ADD CONSTRAINT study_phases_game_type_check
+    CHECK (game_type IN ('pretest', 'training', 'posttest'));
+
+-- Fails if review games exist; delete them first to roll back.
+ALTER TABLE games DROP CONSTRAINT games_game_type_check;
+ALTER TABLE games
+    ADD CONSTRAINT games_game_type_check
+    CHECK (game_type IN ('pretest', 'training', 'posttest'));
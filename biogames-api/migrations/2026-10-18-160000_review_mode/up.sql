@@ -0,0 +1,39 @@
+-- 'review' games replay cores the player previously got wrong.
+ALTER TABLE games DROP CONSTRAINT games_game_type_check;
+ALTER TABLE games
+    ADD CONSTRAINT games_game_type_check
+    CHECK (game_type IN ('pretest', 'training', 'posttest', 'review'));
+
+ALTER TABLE study_phases DROP CONSTRAINT study_phases_game_type_check;
+ALTER TABLE study_phases
+    ADD CONSTRAINT study_phases_game_type_check
+    CHECK (game_type IN ('pretest', 'training', 'posttest', 'review'));
+
+ALTER TABLE study_phases DROP CONSTRAINT study_phases_unlocks_after_check;
+ALTER TABLE study_phases
+    ADD CONSTRAINT study_phases_unlocks_after_check
+    CHECK (unlocks_after IN ('pretest', 'training', 'posttest', 'review'));
+
+ALTER TABLE study_phase_prerequisites DROP CONSTRAINT study_phase_prerequisites_required_game_type_check;
+ALTER TABLE study_phase_prerequisites
+    ADD CONSTRAINT study_phase_prerequisites_required_game_type_check
+    CHECK (required_game_type IN ('pretest', 'training', 'posttest', 'review'));
+
+-- review_mistakes: drawn from the player's own mis-scored cores rather than a
+-- fixed pool.
+ALTER TABLE study_phases DROP CONSTRAINT study_phases_core_set_check;
+ALTER TABLE study_phases
+    ADD CONSTRAINT study_phases_core_set_check
+    CHECK (core_set IN ('held_out_test', 'training_pool', 'review_mistakes'));
+
+-- Unlimited review games once the player has trained at least once.
+INSERT INTO study_phases (protocol_id, game_type, position, max_attempts, challenges_per_game, core_set)
+SELECT id, 'review', 4, NULL, 20, 'review_mistakes'
+FROM study_protocols
+WHERE name = 'default';
+
+INSERT INTO study_phase_prerequisites (phase_id, required_game_type, min_games)
+SELECT study_phases.id, 'training', 1
+FROM study_phases
+JOIN study_protocols ON study_protocols.id = study_phases.protocol_id
+WHERE study_protocols.name = 'default' AND study_phases.game_type = 'review';
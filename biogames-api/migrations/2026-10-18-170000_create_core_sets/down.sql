@@ -0,0 +1,18 @@
+ALTER TABLE games DROP COLUMN core_set;
+
+ALTER TABLE study_phases DROP CONSTRAINT study_phases_core_set_fkey;
+UPDATE study_phases SET core_set = 'held_out_test' WHERE core_set = 'pretest_v1';
+UPDATE study_phases SET core_set = 'review_mistakes' WHERE draw = 'review_mistakes';
+UPDATE study_phases SET core_set = 'training_pool'
+WHERE core_set NOT IN ('held_out_test', 'training_pool', 'review_mistakes');
+ALTER TABLE study_phases
+    ADD CONSTRAINT study_phases_core_set_check
+    CHECK (core_set IN ('held_out_test', 'training_pool', 'review_mistakes'));
+ALTER TABLE study_phases DROP COLUMN draw;
+
+DROP TRIGGER core_sets_frozen_guard ON core_sets;
+DROP FUNCTION core_sets_frozen_guard();
+DROP TRIGGER core_set_members_frozen_guard ON core_set_members;
+DROP FUNCTION core_set_members_frozen_guard();
+DROP TABLE core_set_members;
+DROP TABLE core_sets;
@@ -0,0 +1,115 @@
+-- Named sets of HER2 cores that phases draw their challenges from, replacing
+-- the held-out test list that was compiled into create_game.
+CREATE TABLE core_sets (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    description TEXT,
+    -- A frozen set can no longer change, so every game drawn from it saw the
+    -- same cores. Freezing is one-way.
+    frozen BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    frozen_at TIMESTAMPTZ,
+    CHECK (frozen = (frozen_at IS NOT NULL))
+);
+
+CREATE TABLE core_set_members (
+    core_set_id INTEGER NOT NULL REFERENCES core_sets (id) ON DELETE CASCADE,
+    core_id INTEGER NOT NULL REFERENCES her2_cores (id),
+    PRIMARY KEY (core_set_id, core_id)
+);
+
+CREATE INDEX core_set_members_core_id_idx ON core_set_members (core_id);
+
+CREATE FUNCTION core_set_members_frozen_guard() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP IN ('UPDATE', 'DELETE') AND EXISTS (SELECT 1 FROM core_sets WHERE id = OLD.core_set_id AND frozen) THEN
+        RAISE EXCEPTION 'core set % is frozen', OLD.core_set_id;
+    END IF;
+    IF TG_OP IN ('INSERT', 'UPDATE') AND EXISTS (SELECT 1 FROM core_sets WHERE id = NEW.core_set_id AND frozen) THEN
+        RAISE EXCEPTION 'core set % is frozen', NEW.core_set_id;
+    END IF;
+    RETURN COALESCE(NEW, OLD);
+END
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER core_set_members_frozen_guard
+    BEFORE INSERT OR UPDATE OR DELETE ON core_set_members
+    FOR EACH ROW EXECUTE FUNCTION core_set_members_frozen_guard();
+
+CREATE FUNCTION core_sets_frozen_guard() RETURNS trigger AS $$
+BEGIN
+    IF OLD.frozen AND (TG_OP = 'DELETE' OR NOT NEW.frozen OR NEW.name <> OLD.name) THEN
+        RAISE EXCEPTION 'core set % is frozen', OLD.name;
+    END IF;
+    RETURN COALESCE(NEW, OLD);
+END
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER core_sets_frozen_guard
+    BEFORE UPDATE OR DELETE ON core_sets
+    FOR EACH ROW EXECUTE FUNCTION core_sets_frozen_guard();
+
+-- The held-out list exactly as it was in the code, frozen.
+INSERT INTO core_sets (name, description)
+VALUES ('pretest_v1', 'Held-out cores for the pretest and posttest');
+
+INSERT INTO core_set_members (core_set_id, core_id)
+SELECT core_sets.id, her2_cores.id
+FROM core_sets, her2_cores
+WHERE core_sets.name = 'pretest_v1'
+  AND her2_cores.id = ANY (ARRAY[
+      345, 20125, 23246, 6134, 9192, 4376, 1162, 22787, 9809, 19324,
+      2907, 14342, 14795, 438, 12330, 10186, 8781, 12076, 19052, 6547,
+      5077, 8050, 9934, 23774, 10636, 13660, 20394, 18529, 19444, 4625,
+      19430, 23853, 210, 16056, 5231, 940, 8939, 22438, 12988, 15627,
+      3138, 18219, 18021, 19185, 22208, 22696, 15629, 9052, 23770, 18238
+  ]);
+
+DO $$
+DECLARE
+    seeded INTEGER;
+BEGIN
+    SELECT count(*) INTO seeded
+    FROM core_set_members JOIN core_sets ON core_sets.id = core_set_members.core_set_id
+    WHERE core_sets.name = 'pretest_v1';
+    IF seeded < 50 THEN
+        RAISE WARNING 'pretest_v1 has % of 50 cores; her2_cores is missing the rest', seeded;
+    END IF;
+END
+$$;
+
+UPDATE core_sets SET frozen = TRUE, frozen_at = now() WHERE name = 'pretest_v1';
+
+-- Training drew from every core outside the held-out list. Left unfrozen so
+-- newly imported cores can be added to it.
+INSERT INTO core_sets (name, description)
+VALUES ('training_pool', 'Every core not held out for testing');
+
+INSERT INTO core_set_members (core_set_id, core_id)
+SELECT training.id, her2_cores.id
+FROM core_sets AS training, her2_cores
+WHERE training.name = 'training_pool'
+  AND her2_cores.id NOT IN (
+      SELECT core_id FROM core_set_members
+      JOIN core_sets ON core_sets.id = core_set_members.core_set_id
+      WHERE core_sets.name = 'pretest_v1'
+  );
+
+-- Phases name the set they draw from; `draw` says how. Review games draw the
+-- player's mistakes from within their set.
+ALTER TABLE study_phases
+    ADD COLUMN draw VARCHAR NOT NULL DEFAULT 'random' CHECK (draw IN ('random', 'review_mistakes'));
+
+ALTER TABLE study_phases DROP CONSTRAINT study_phases_core_set_check;
+
+UPDATE study_phases SET draw = 'review_mistakes', core_set = 'training_pool' WHERE core_set = 'review_mistakes';
+UPDATE study_phases SET core_set = 'pretest_v1' WHERE core_set = 'held_out_test';
+ALTER TABLE study_phases
+    ADD CONSTRAINT study_phases_core_set_fkey
+    FOREIGN KEY (core_set) REFERENCES core_sets (name) ON UPDATE CASCADE;
+
+-- Which set each game drew from, for analysis across set versions.
+ALTER TABLE games ADD COLUMN core_set TEXT;
+
+UPDATE games
+SET core_set = CASE WHEN game_type IN ('pretest', 'posttest') THEN 'pretest_v1' ELSE 'training_pool' END;
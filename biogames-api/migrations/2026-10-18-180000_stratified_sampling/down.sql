@@ -0,0 +1,4 @@
+ALTER TABLE games DROP COLUMN sampling;
+ALTER TABLE study_phases DROP CONSTRAINT study_phases_sampling_counts;
+ALTER TABLE study_phases DROP COLUMN sampling_counts;
+ALTER TABLE study_phases DROP COLUMN sampling;
@@ -0,0 +1,28 @@
+-- How a phase spreads its random draw over the HER2 score classes (0-3):
+--   uniform       ignore the classes (the previous behaviour)
+--   balanced      the same number of cores of each score
+--   proportional  each score in proportion to its share of the core set
+--   explicit      sampling_counts[score + 1] cores of each score
+-- Classes the set runs short of are topped up from the others. Review phases
+-- draw the player's mistakes and ignore this.
+ALTER TABLE study_phases
+    ADD COLUMN sampling VARCHAR NOT NULL DEFAULT 'uniform'
+        CHECK (sampling IN ('uniform', 'balanced', 'proportional', 'explicit')),
+    ADD COLUMN sampling_counts INTEGER[],
+    ADD CONSTRAINT study_phases_sampling_counts CHECK (
+        (sampling = 'explicit') = (sampling_counts IS NOT NULL)
+        AND (sampling_counts IS NULL OR (
+            array_length(sampling_counts, 1) = 4
+            AND array_ndims(sampling_counts) = 1
+            AND 0 <= ALL (sampling_counts)
+        ))
+    );
+
+-- A 20-core training game drawn uniformly can end up with almost no 3+ cores.
+UPDATE study_phases SET sampling = 'balanced' WHERE game_type = 'training';
+
+-- The strategy each game was drawn with. NULL for review games, which are
+-- weighted by the player's mistakes instead.
+ALTER TABLE games ADD COLUMN sampling VARCHAR;
+
+UPDATE games SET sampling = 'uniform' WHERE game_type <> 'review';
@@ -0,0 +1,4 @@
+ALTER TABLE study_phases DROP COLUMN fixed_seed;
+ALTER TABLE games DROP COLUMN sampling_counts;
+ALTER TABLE games DROP COLUMN initial_core_id;
+ALTER TABLE games DROP COLUMN seed;
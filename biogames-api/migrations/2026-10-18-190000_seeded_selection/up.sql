@@ -0,0 +1,14 @@
+-- Challenges are now drawn by the API with a seeded RNG, and the inputs the
+-- draw depends on are kept so /admin/games/:id/selection can re-derive it.
+-- All NULL for games drawn before this by Postgres random().
+ALTER TABLE games
+    ADD COLUMN seed BIGINT,
+    -- The preview core the player already saw, placed first and left out of the draw.
+    ADD COLUMN initial_core_id INTEGER REFERENCES her2_cores (id),
+    -- Copied from the phase for explicit sampling, like time_limit_secs.
+    ADD COLUMN sampling_counts INTEGER[];
+
+-- When set, every game of the phase uses this seed instead of a fresh one,
+-- so all participants get the same cores in the same order (e.g. a fixed
+-- pretest/posttest sequence). The preview core is not used for these.
+ALTER TABLE study_phases ADD COLUMN fixed_seed BIGINT;
@@ -0,0 +1,4 @@
+ALTER TABLE games DROP COLUMN test_form;
+DROP TABLE form_assignments;
+ALTER TABLE study_phases DROP COLUMN uses_test_form;
+DROP TABLE test_forms;
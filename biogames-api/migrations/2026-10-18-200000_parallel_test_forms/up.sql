@@ -0,0 +1,36 @@
+-- Parallel test forms: disjoint, difficulty-matched core sets that the
+-- protocol's form phases (pretest and posttest) rotate through, so no
+-- participant is tested twice on the same cores. Setting a study up:
+--   1. create and freeze one core set per form (POST /admin/core-sets),
+--   2. insert a test_forms row per set, positions 0, 1, ...,
+--   3. set uses_test_form on the phases that should rotate.
+CREATE TABLE test_forms (
+    id SERIAL PRIMARY KEY,
+    protocol_id INTEGER NOT NULL REFERENCES study_protocols (id),
+    name TEXT NOT NULL,
+    position INTEGER NOT NULL CHECK (position >= 0),
+    core_set TEXT NOT NULL REFERENCES core_sets (name) ON UPDATE CASCADE,
+    UNIQUE (protocol_id, name),
+    UNIQUE (protocol_id, position),
+    UNIQUE (protocol_id, core_set)
+);
+
+-- A form phase draws from the participant's assigned form instead of
+-- core_set.
+ALTER TABLE study_phases ADD COLUMN uses_test_form BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- Participants are numbered in the order they're first assigned; rotation is
+-- that number modulo the number of forms. The k-th form phase (in protocol
+-- order) gets the form at position (rotation + k) mod forms, so with forms A
+-- and B half the cohort takes A then B and half B then A.
+CREATE TABLE form_assignments (
+    user_id TEXT NOT NULL REFERENCES registered_users (user_id),
+    phase_id INTEGER NOT NULL REFERENCES study_phases (id),
+    test_form_id INTEGER NOT NULL REFERENCES test_forms (id),
+    rotation INTEGER NOT NULL CHECK (rotation >= 0),
+    assigned_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (user_id, phase_id)
+);
+
+-- The form each game was drawn from; NULL outside form phases.
+ALTER TABLE games ADD COLUMN test_form TEXT;
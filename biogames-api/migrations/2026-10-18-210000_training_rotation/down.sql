@@ -0,0 +1,5 @@
+ALTER TABLE games DROP COLUMN reshow_missed_rate;
+ALTER TABLE games DROP COLUMN no_repeat;
+ALTER TABLE study_phases DROP CONSTRAINT study_phases_reshow_needs_no_repeat;
+ALTER TABLE study_phases DROP COLUMN reshow_missed_rate;
+ALTER TABLE study_phases DROP COLUMN no_repeat;
@@ -0,0 +1,17 @@
+-- no_repeat: draw cores the participant has never been served before, and
+-- only recycle served ones once the set has none left. reshow_missed_rate is
+-- the share of each game deliberately filled with cores they previously
+-- mis-scored (timeouts aside), e.g. 0.2 re-shows 4 of 20.
+ALTER TABLE study_phases
+    ADD COLUMN no_repeat BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN reshow_missed_rate DOUBLE PRECISION NOT NULL DEFAULT 0
+        CHECK (reshow_missed_rate >= 0 AND reshow_missed_rate <= 1),
+    ADD CONSTRAINT study_phases_reshow_needs_no_repeat CHECK (reshow_missed_rate = 0 OR no_repeat);
+
+UPDATE study_phases SET no_repeat = TRUE WHERE game_type = 'training';
+
+-- Copied from the phase when the game is created so the draw can be
+-- re-derived. reshow_missed_rate is NULL unless no_repeat.
+ALTER TABLE games
+    ADD COLUMN no_repeat BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN reshow_missed_rate DOUBLE PRECISION;
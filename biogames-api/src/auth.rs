@@ -0,0 +1,25 @@
+use axum::http::{header, HeaderMap};
+
+use crate::error::ApiError;
+
+/// Requires `Authorization: Bearer <token>`. Routes guarded this way stay
+/// closed while their token isn't configured; `env_var` names it in the
+/// warning.
+pub fn require_bearer(headers: &HeaderMap, token: Option<&str>, env_var: &str) -> Result<(), ApiError> {
+    let Some(expected) = token else {
+        tracing::warn!("{} not set", env_var);
+        return Err(ApiError::Unauthorized);
+    };
+    check_bearer(headers, expected)
+}
+
+/// Checks the request's bearer token against `expected`.
+pub fn check_bearer(headers: &HeaderMap, expected: &str) -> Result<(), ApiError> {
+    let authorized = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|value| value == expected);
+
+    if authorized { Ok(()) } else { Err(ApiError::Unauthorized) }
+}
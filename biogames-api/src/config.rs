@@ -1,38 +1,738 @@
 use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::path::{Path, PathBuf};
-use once_cell::sync::Lazy;
-
-// Get the image base path from environment variable or use the default USB path
-pub static IMAGE_BASE_PATH: Lazy<PathBuf> = Lazy::new(|| {
-    env::var("IMAGE_BASE_PATH")
-        .map(PathBuf::from)
-        .unwrap_or_else(|_| PathBuf::from("/home/biogames/biogames-repo/Data_WebP"))
-});
-
-// Function to get the full path of an image
-pub fn get_image_path(file_name: &str) -> PathBuf {
-    let mut full_path = IMAGE_BASE_PATH.clone();
-    
-    // Extract just the filename without the path
-    let base_name = Path::new(file_name).file_name()
-        .map(|f| f.to_string_lossy().to_string())
-        .unwrap_or_else(|| file_name.to_string());
-    
-    // Try to find the file in the root directory of the USB drive
-    full_path.push(&base_name);
-    
-    // If we can't find it there, check subdirectories based on score (0, 1, 2, 3)
-    if !full_path.exists() {
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::Deserialize;
+use thiserror::Error;
+
+/// Optional TOML file read before environment overrides are applied.
+/// `BIOGAMES_CONFIG` points at it explicitly; otherwise `biogames.toml` in the
+/// working directory is used if it exists.
+const DEFAULT_CONFIG_FILE: &str = "biogames.toml";
+
+/// Every deployment knob the API understands. Values come from the TOML file
+/// (if any) and are then overridden by environment variables, so a `.env` or
+/// a supervisor's environment always wins over the checked-in file.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AppConfig {
+    pub server: ServerConfig,
+    pub logging: LoggingConfig,
+    pub tls: TlsConfig,
+    pub cors: CorsConfig,
+    pub rate_limit: RateLimitConfig,
+    pub database: DatabaseConfig,
+    pub images: ImageConfig,
+    pub analytics: AnalyticsConfig,
+    pub metrics: MetricsConfig,
+    pub admin: AdminConfig,
+    pub registration: RegistrationConfig,
+    pub sweeper: SweeperConfig,
+    pub scoring: ScoringConfig,
+    pub review: ReviewConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// `BIND_ADDRESS`
+    pub bind_address: IpAddr,
+    /// `PORT`
+    pub port: u16,
+    /// `SHUTDOWN_GRACE_SECS`. How long in-flight requests may take to finish
+    /// after SIGTERM before the remaining connections are dropped.
+    pub shutdown_grace_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// `LOG_FORMAT`: `text` for humans, `json` for log shippers.
+    pub format: LogFormat,
+    /// Default `tracing` filter directives. `RUST_LOG`, when set, wins.
+    pub filter: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err("expected \"text\" or \"json\"".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// `TLS_ENABLED`. Serves HTTPS on `server.port` when true.
+    pub enabled: bool,
+    /// `TLS_CERT_PATH`, PEM certificate chain
+    pub cert_path: PathBuf,
+    /// `TLS_KEY_PATH`, PEM private key
+    pub key_path: PathBuf,
+    /// `TLS_REDIRECT_HTTP_PORT`. When set, a plain HTTP listener on this port
+    /// answers every request with a redirect to HTTPS.
+    pub redirect_http_port: Option<u16>,
+    /// `TLS_RELOAD_POLL_SECS`. How often the certificate files are checked for
+    /// changes; 0 disables polling (SIGHUP still triggers a reload).
+    pub reload_poll_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// `CORS_ALLOWED_ORIGINS`, comma separated. Exact origins
+    /// (`https://biogames.ucla.edu`) or one leading wildcard label
+    /// (`https://*.ucla.edu`). Empty means same-origin only.
+    pub allowed_origins: Vec<String>,
+    /// `CORS_ALLOWED_METHODS`, comma separated
+    pub allowed_methods: Vec<String>,
+    /// `CORS_ALLOWED_HEADERS`, comma separated
+    pub allowed_headers: Vec<String>,
+    /// `CORS_MAX_AGE_SECS`. How long browsers may cache a preflight answer.
+    pub max_age_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// `RATE_LIMIT_ENABLED`
+    pub enabled: bool,
+    /// `RATE_LIMIT_TRUSTED_PROXIES`, comma separated IPs. Requests from these
+    /// peers are keyed by the client address in `X-Forwarded-For` instead of
+    /// the proxy's own address. Defaults to loopback, where the `/proxy-api`
+    /// reverse proxy runs; an empty list trusts no one.
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Per client IP on every public route not listed below.
+    /// `RATE_LIMIT_DEFAULT_BURST`, `RATE_LIMIT_DEFAULT_PER_MINUTE`
+    pub default: RatePolicy,
+    /// Per client IP on the user id lookups (`/validate-username`,
+    /// `/check-username`, `/check-game-type`, `/users/:user_id/active-game`,
+    /// `/users/:user_id/progress`), which could otherwise be used to
+    /// enumerate the id space. `RATE_LIMIT_LOOKUP_BURST`, `RATE_LIMIT_LOOKUP_PER_MINUTE`
+    pub lookup: RatePolicy,
+    /// Per client IP on `/generate-user-id`, which sends an email, and
+    /// `/register-with-username`, which takes a new user id per request.
+    /// `RATE_LIMIT_REGISTRATION_BURST`, `RATE_LIMIT_REGISTRATION_PER_MINUTE`
+    pub registration: RatePolicy,
+    /// Per user id on game creation, username registration and submissions.
+    /// `RATE_LIMIT_PER_USER_BURST`, `RATE_LIMIT_PER_USER_PER_MINUTE`
+    pub per_user: RatePolicy,
+}
+
+/// A token bucket: up to `burst` requests at once, refilled continuously at
+/// `per_minute`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RatePolicy {
+    pub burst: u32,
+    pub per_minute: f64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// `DATABASE_URL` (required)
+    pub url: String,
+    /// `DB_POOL_MAX_SIZE`
+    pub pool_max_size: u32,
+    /// `DB_POOL_TIMEOUT_SECS`
+    pub pool_timeout_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImageConfig {
+    /// `IMAGE_BASE_PATH`
+    pub base_path: PathBuf,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnalyticsConfig {
+    /// `ANALYTICS_TOKEN`. The CSV exports reject every request while unset.
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// `METRICS_TOKEN`. When set, `/metrics` requires `Authorization: Bearer <token>`.
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// `ADMIN_TOKEN`. The `/admin` routes reject every request while unset.
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RegistrationConfig {
+    /// `BYPASS_EMAIL_VALIDATION`. Accepts any email domain when true.
+    pub bypass_email_validation: bool,
+    /// `ALLOWED_EMAIL_DOMAINS`, comma separated (e.g. `@ucla.edu,@mednet.ucla.edu`).
+    pub allowed_email_domains: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SweeperConfig {
+    /// `SWEEPER_ENABLED`
+    pub enabled: bool,
+    /// `SWEEPER_INTERVAL_SECS`. How often to look for abandoned games.
+    pub interval_secs: u64,
+    /// `SWEEPER_IDLE_TIMEOUT_MINS`. A game with no challenge started or
+    /// submitted for this long is finalized as abandoned.
+    pub idle_timeout_mins: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScoringConfig {
+    /// `SCORING_TIMEOUT_PENALTY`. Points deducted for a challenge of a timed
+    /// game answered after its deadline, in place of the guess's score.
+    pub timeout_penalty: i32,
+    /// `SCORING_DEADLINE_GRACE_MS`. Slack after the deadline for network
+    /// latency, so an answer the client's countdown accepted isn't timed out.
+    pub deadline_grace_ms: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReviewConfig {
+    /// `REVIEW_MAX_CHALLENGES`. Upper bound on the length of a review game, on
+    /// top of the review phase's `challenges_per_game`.
+    pub max_challenges: i32,
+    /// `REVIEW_RECENCY_HALF_LIFE_DAYS`. A mistake this many days old is half
+    /// as likely to be picked as one made today.
+    pub recency_half_life_days: f64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 3001,
+            shutdown_grace_secs: 30,
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            format: LogFormat::Text,
+            filter: "biogames_api=debug,tower_http=debug".to_string(),
+        }
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            enabled: false,
+            cert_path: PathBuf::from("cert").join("cert.pem"),
+            key_path: PathBuf::from("cert").join("key.pem"),
+            redirect_http_port: None,
+            reload_poll_secs: 60,
+        }
+    }
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        // The client is served through a same-origin proxy (`/proxy-api`), so
+        // nothing needs cross-origin access unless a deployment opts in.
+        CorsConfig {
+            allowed_origins: Vec::new(),
+            allowed_methods: vec!["GET".to_string(), "POST".to_string()],
+            allowed_headers: vec!["content-type".to_string(), "x-request-id".to_string()],
+            max_age_secs: 600,
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            enabled: true,
+            // The client's `/proxy-api` proxy runs on the same host; without
+            // trusting it the whole cohort would share its bucket.
+            trusted_proxies: vec![IpAddr::V4(Ipv4Addr::LOCALHOST), IpAddr::V6(Ipv6Addr::LOCALHOST)],
+            // A game is roughly three requests per challenge plus image
+            // prefetches; leave plenty of room for a classroom behind one NAT.
+            default: RatePolicy { burst: 300, per_minute: 600.0 },
+            lookup: RatePolicy { burst: 20, per_minute: 10.0 },
+            registration: RatePolicy { burst: 5, per_minute: 0.2 },
+            per_user: RatePolicy { burst: 30, per_minute: 60.0 },
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            url: String::new(),
+            pool_max_size: 16,
+            pool_timeout_secs: 5,
+        }
+    }
+}
+
+impl Default for ImageConfig {
+    fn default() -> Self {
+        // The USB drive the cores were shipped on
+        ImageConfig { base_path: PathBuf::from("/home/biogames/biogames-repo/Data_WebP") }
+    }
+}
+
+impl Default for SweeperConfig {
+    fn default() -> Self {
+        SweeperConfig {
+            enabled: true,
+            interval_secs: 300,
+            idle_timeout_mins: 120,
+        }
+    }
+}
+
+impl Default for ScoringConfig {
+    fn default() -> Self {
+        // As bad as the worst wrong answer, so running out the clock never pays
+        ScoringConfig {
+            timeout_penalty: 5,
+            deadline_grace_ms: 2000,
+        }
+    }
+}
+
+impl Default for ReviewConfig {
+    fn default() -> Self {
+        ReviewConfig {
+            max_challenges: 20,
+            recency_half_life_days: 14.0,
+        }
+    }
+}
+
+impl Default for RegistrationConfig {
+    fn default() -> Self {
+        RegistrationConfig {
+            bypass_email_validation: false,
+            allowed_email_domains: vec![
+                "@ucla.edu".to_string(),
+                "@mednet.ucla.edu".to_string(),
+                "@mail.huji.ac.il".to_string(),
+                "@hadassah.org.il".to_string(),
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Failed to read config file {path}: {source}")]
+    Read { path: PathBuf, source: std::io::Error },
+
+    #[error("Failed to parse config file {path}: {source}")]
+    Parse { path: PathBuf, source: toml::de::Error },
+
+    #[error("Invalid configuration:\n  - {}", .0.join("\n  - "))]
+    Invalid(Vec<String>),
+}
+
+impl AppConfig {
+    /// Loads the TOML file (if present), applies environment overrides and
+    /// validates the result. All problems are reported at once so a broken
+    /// deployment can be fixed in a single pass.
+    pub fn load() -> Result<AppConfig, ConfigError> {
+        let mut config = match config_file_path() {
+            Some(path) => Self::from_file(&path)?,
+            None => AppConfig::default(),
+        };
+
+        let mut problems = Vec::new();
+        config.apply_env(&mut problems);
+        config.validate(&mut problems);
+
+        if problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+
+    fn from_file(path: &Path) -> Result<AppConfig, ConfigError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|source| ConfigError::Read { path: path.to_path_buf(), source })?;
+        toml::from_str(&contents)
+            .map_err(|source| ConfigError::Parse { path: path.to_path_buf(), source })
+    }
+
+    fn apply_env(&mut self, problems: &mut Vec<String>) {
+        env_parsed("BIND_ADDRESS", &mut self.server.bind_address, problems);
+        env_parsed("PORT", &mut self.server.port, problems);
+        env_parsed("SHUTDOWN_GRACE_SECS", &mut self.server.shutdown_grace_secs, problems);
+
+        env_parsed("LOG_FORMAT", &mut self.logging.format, problems);
+
+        env_parsed("TLS_ENABLED", &mut self.tls.enabled, problems);
+        if let Ok(path) = env::var("TLS_CERT_PATH") {
+            self.tls.cert_path = PathBuf::from(path);
+        }
+        if let Ok(path) = env::var("TLS_KEY_PATH") {
+            self.tls.key_path = PathBuf::from(path);
+        }
+        if let Ok(raw) = env::var("TLS_REDIRECT_HTTP_PORT") {
+            match raw.trim() {
+                "" => self.tls.redirect_http_port = None,
+                port => match port.parse() {
+                    Ok(port) => self.tls.redirect_http_port = Some(port),
+                    Err(e) => problems.push(format!("TLS_REDIRECT_HTTP_PORT has invalid value {:?}: {}", raw, e)),
+                },
+            }
+        }
+        env_parsed("TLS_RELOAD_POLL_SECS", &mut self.tls.reload_poll_secs, problems);
+
+        env_list("CORS_ALLOWED_ORIGINS", &mut self.cors.allowed_origins);
+        env_list("CORS_ALLOWED_METHODS", &mut self.cors.allowed_methods);
+        env_list("CORS_ALLOWED_HEADERS", &mut self.cors.allowed_headers);
+        env_parsed("CORS_MAX_AGE_SECS", &mut self.cors.max_age_secs, problems);
+
+        env_parsed("RATE_LIMIT_ENABLED", &mut self.rate_limit.enabled, problems);
+        if let Ok(raw) = env::var("RATE_LIMIT_TRUSTED_PROXIES") {
+            self.rate_limit.trusted_proxies.clear();
+            for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+                match entry.parse() {
+                    Ok(ip) => self.rate_limit.trusted_proxies.push(ip),
+                    Err(e) => problems.push(format!("RATE_LIMIT_TRUSTED_PROXIES has invalid entry {:?}: {}", entry, e)),
+                }
+            }
+        }
+        for (prefix, policy) in [
+            ("RATE_LIMIT_DEFAULT", &mut self.rate_limit.default),
+            ("RATE_LIMIT_LOOKUP", &mut self.rate_limit.lookup),
+            ("RATE_LIMIT_REGISTRATION", &mut self.rate_limit.registration),
+            ("RATE_LIMIT_PER_USER", &mut self.rate_limit.per_user),
+        ] {
+            env_parsed(&format!("{}_BURST", prefix), &mut policy.burst, problems);
+            env_parsed(&format!("{}_PER_MINUTE", prefix), &mut policy.per_minute, problems);
+        }
+
+        if let Ok(url) = env::var("DATABASE_URL") {
+            self.database.url = url;
+        }
+        env_parsed("DB_POOL_MAX_SIZE", &mut self.database.pool_max_size, problems);
+        env_parsed("DB_POOL_TIMEOUT_SECS", &mut self.database.pool_timeout_secs, problems);
+
+        if let Ok(path) = env::var("IMAGE_BASE_PATH") {
+            self.images.base_path = PathBuf::from(path);
+        }
+
+        if let Ok(token) = env::var("ANALYTICS_TOKEN") {
+            self.analytics.token = Some(token);
+        }
+        if let Ok(token) = env::var("METRICS_TOKEN") {
+            self.metrics.token = Some(token);
+        }
+        if let Ok(token) = env::var("ADMIN_TOKEN") {
+            self.admin.token = Some(token);
+        }
+
+        env_parsed("BYPASS_EMAIL_VALIDATION", &mut self.registration.bypass_email_validation, problems);
+        env_list("ALLOWED_EMAIL_DOMAINS", &mut self.registration.allowed_email_domains);
+
+        env_parsed("SWEEPER_ENABLED", &mut self.sweeper.enabled, problems);
+        env_parsed("SWEEPER_INTERVAL_SECS", &mut self.sweeper.interval_secs, problems);
+        env_parsed("SWEEPER_IDLE_TIMEOUT_MINS", &mut self.sweeper.idle_timeout_mins, problems);
+
+        env_parsed("SCORING_TIMEOUT_PENALTY", &mut self.scoring.timeout_penalty, problems);
+        env_parsed("SCORING_DEADLINE_GRACE_MS", &mut self.scoring.deadline_grace_ms, problems);
+
+        env_parsed("REVIEW_MAX_CHALLENGES", &mut self.review.max_challenges, problems);
+        env_parsed("REVIEW_RECENCY_HALF_LIFE_DAYS", &mut self.review.recency_half_life_days, problems);
+    }
+
+    fn validate(&mut self, problems: &mut Vec<String>) {
+        if self.tls.enabled {
+            for (name, path) in [("TLS_CERT_PATH (tls.cert_path)", &self.tls.cert_path), ("TLS_KEY_PATH (tls.key_path)", &self.tls.key_path)] {
+                if !path.is_file() {
+                    problems.push(format!("{} {} does not exist or is not a file", name, path.display()));
+                }
+            }
+            if self.tls.redirect_http_port == Some(self.server.port) {
+                problems.push("TLS_REDIRECT_HTTP_PORT (tls.redirect_http_port) must differ from PORT (server.port)".to_string());
+            }
+        } else if self.tls.redirect_http_port.is_some() {
+            problems.push("TLS_REDIRECT_HTTP_PORT (tls.redirect_http_port) is set but TLS_ENABLED is false".to_string());
+        }
+        self.validate_cors(problems);
+        for (name, policy) in [
+            ("default", &self.rate_limit.default),
+            ("lookup", &self.rate_limit.lookup),
+            ("registration", &self.rate_limit.registration),
+            ("per_user", &self.rate_limit.per_user),
+        ] {
+            if policy.burst == 0 {
+                problems.push(format!("rate_limit.{}.burst must be at least 1", name));
+            }
+            if !(policy.per_minute.is_finite() && policy.per_minute > 0.0) {
+                problems.push(format!("rate_limit.{}.per_minute must be a positive number", name));
+            }
+        }
+        if self.database.url.trim().is_empty() {
+            problems.push("DATABASE_URL (database.url) is not set".to_string());
+        }
+        if self.database.pool_max_size == 0 {
+            problems.push("DB_POOL_MAX_SIZE (database.pool_max_size) must be at least 1".to_string());
+        }
+        if self.database.pool_timeout_secs == 0 {
+            problems.push("DB_POOL_TIMEOUT_SECS (database.pool_timeout_secs) must be at least 1".to_string());
+        }
+        if self.sweeper.interval_secs == 0 {
+            problems.push("SWEEPER_INTERVAL_SECS (sweeper.interval_secs) must be at least 1".to_string());
+        }
+        if self.sweeper.idle_timeout_mins == 0 {
+            problems.push("SWEEPER_IDLE_TIMEOUT_MINS (sweeper.idle_timeout_mins) must be at least 1".to_string());
+        }
+        if self.scoring.timeout_penalty < 0 {
+            problems.push("SCORING_TIMEOUT_PENALTY (scoring.timeout_penalty) must not be negative".to_string());
+        }
+        if self.review.max_challenges < 1 {
+            problems.push("REVIEW_MAX_CHALLENGES (review.max_challenges) must be at least 1".to_string());
+        }
+        if !(self.review.recency_half_life_days.is_finite() && self.review.recency_half_life_days > 0.0) {
+            problems.push("REVIEW_RECENCY_HALF_LIFE_DAYS (review.recency_half_life_days) must be a positive number".to_string());
+        }
+        if self.analytics.token.as_deref().is_some_and(|t| t.trim().is_empty()) {
+            self.analytics.token = None;
+        }
+        if self.metrics.token.as_deref().is_some_and(|t| t.trim().is_empty()) {
+            self.metrics.token = None;
+        }
+        if self.admin.token.as_deref().is_some_and(|t| t.trim().is_empty()) {
+            self.admin.token = None;
+        }
+
+        // Normalize to "@domain" so suffix matching can't accept "evilucla.edu"
+        self.registration.allowed_email_domains = self.registration.allowed_email_domains
+            .iter()
+            .map(|d| d.trim().to_lowercase())
+            .filter(|d| !d.is_empty())
+            .map(|d| if d.starts_with('@') { d } else { format!("@{}", d) })
+            .collect();
+        if !self.registration.bypass_email_validation && self.registration.allowed_email_domains.is_empty() {
+            problems.push(
+                "ALLOWED_EMAIL_DOMAINS (registration.allowed_email_domains) is empty, so nobody could register; \
+                 set BYPASS_EMAIL_VALIDATION=true to accept any domain".to_string()
+            );
+        }
+    }
+
+    fn validate_cors(&mut self, problems: &mut Vec<String>) {
+        let cors = &mut self.cors;
+        cors.allowed_origins = normalized_list(&cors.allowed_origins, |o| o.trim_end_matches('/').to_lowercase());
+        cors.allowed_methods = normalized_list(&cors.allowed_methods, |m| m.to_uppercase());
+        cors.allowed_headers = normalized_list(&cors.allowed_headers, |h| h.to_lowercase());
+
+        for origin in &cors.allowed_origins {
+            if let Err(e) = crate::cors::OriginRule::parse(origin) {
+                problems.push(format!("CORS_ALLOWED_ORIGINS (cors.allowed_origins) entry {:?} {}", origin, e));
+            }
+        }
+        for method in &cors.allowed_methods {
+            if axum::http::Method::from_str(method).is_err() {
+                problems.push(format!("CORS_ALLOWED_METHODS (cors.allowed_methods) entry {:?} is not an HTTP method", method));
+            }
+        }
+        for header in &cors.allowed_headers {
+            if axum::http::HeaderName::from_str(header).is_err() || header == "*" {
+                problems.push(format!("CORS_ALLOWED_HEADERS (cors.allowed_headers) entry {:?} is not a header name", header));
+            }
+        }
+    }
+
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.server.bind_address, self.server.port)
+    }
+
+    pub fn shutdown_grace(&self) -> Duration {
+        Duration::from_secs(self.server.shutdown_grace_secs)
+    }
+
+    pub fn db_pool_timeout(&self) -> Duration {
+        Duration::from_secs(self.database.pool_timeout_secs)
+    }
+
+    /// Whether registration should accept this email address.
+    pub fn is_allowed_email(&self, email: &str) -> bool {
+        self.registration.bypass_email_validation || self.allowed_email_domain(email).is_some()
+    }
+
+    /// The configured domain (e.g. `@ucla.edu`) this email belongs to, if any.
+    pub fn allowed_email_domain(&self, email: &str) -> Option<&str> {
+        let email = email.trim().to_lowercase();
+        self.registration.allowed_email_domains
+            .iter()
+            .find(|domain| email.ends_with(domain.as_str()))
+            .map(String::as_str)
+    }
+
+    /// Resolves a `her2_cores.file_name` to a readable path. The stored path is
+    /// used if it exists; otherwise the file is looked up by name under the
+    /// image base path, first at its root and then in the score subdirectories
+    /// (0, 1, 2, 3).
+    pub async fn resolve_image_path(&self, file_name: &str) -> PathBuf {
+        let stored_path = PathBuf::from(file_name);
+        if tokio::fs::try_exists(&stored_path).await.unwrap_or(false) {
+            return stored_path;
+        }
+
+        // Extract just the filename without the path
+        let base_name = Path::new(file_name).file_name()
+            .map(|f| f.to_string_lossy().to_string())
+            .unwrap_or_else(|| file_name.to_string());
+
+        let full_path = self.images.base_path.join(&base_name);
+        if tokio::fs::try_exists(&full_path).await.unwrap_or(false) {
+            return full_path;
+        }
+
         for subdir in &["0", "1", "2", "3"] {
-            let mut subdir_path = IMAGE_BASE_PATH.clone();
-            subdir_path.push(subdir);
-            subdir_path.push(&base_name);
-            
-            if subdir_path.exists() {
+            let subdir_path = self.images.base_path.join(subdir).join(&base_name);
+            if tokio::fs::try_exists(&subdir_path).await.unwrap_or(false) {
                 return subdir_path;
             }
         }
+
+        full_path
+    }
+
+    /// Logs the effective configuration and CORS policy, with secrets masked.
+    pub fn log_summary(&self) {
+        tracing::info!(
+            "Configuration: listen={}, tls={}, db_pool_max_size={}, db_pool_timeout={}s, image_base_path={}, \
+             analytics_token={}, metrics_token={}, admin_token={}, bypass_email_validation={}, allowed_email_domains=[{}]",
+            self.socket_addr(),
+            if self.tls.enabled {
+                format!(
+                    "on (cert={}, key={}, http_redirect_port={:?})",
+                    self.tls.cert_path.display(),
+                    self.tls.key_path.display(),
+                    self.tls.redirect_http_port
+                )
+            } else {
+                "off".to_string()
+            },
+            self.database.pool_max_size,
+            self.database.pool_timeout_secs,
+            self.images.base_path.display(),
+            if self.analytics.token.is_some() { "set" } else { "unset" },
+            if self.metrics.token.is_some() { "set" } else { "unset" },
+            if self.admin.token.is_some() { "set" } else { "unset" },
+            self.registration.bypass_email_validation,
+            self.registration.allowed_email_domains.join(", ")
+        );
+        tracing::info!(
+            "CORS policy: origins=[{}], methods=[{}], headers=[{}], max_age={}s; \
+             analytics routes are same-origin only",
+            if self.cors.allowed_origins.is_empty() { "none, same-origin only".to_string() } else { self.cors.allowed_origins.join(", ") },
+            self.cors.allowed_methods.join(", "),
+            self.cors.allowed_headers.join(", "),
+            self.cors.max_age_secs
+        );
+        if self.rate_limit.enabled {
+            let rl = &self.rate_limit;
+            tracing::info!(
+                "Rate limits (burst, per minute): default={}/{}, lookup={}/{}, registration={}/{}, per_user={}/{}; trusted_proxies=[{}]",
+                rl.default.burst, rl.default.per_minute,
+                rl.lookup.burst, rl.lookup.per_minute,
+                rl.registration.burst, rl.registration.per_minute,
+                rl.per_user.burst, rl.per_user.per_minute,
+                rl.trusted_proxies.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+            );
+        } else {
+            tracing::warn!("Rate limiting is disabled");
+        }
+        if self.sweeper.enabled {
+            tracing::info!(
+                "Abandoned game sweeper: every {}s, idle timeout {} min",
+                self.sweeper.interval_secs,
+                self.sweeper.idle_timeout_mins
+            );
+        } else {
+            tracing::warn!("Abandoned game sweeper is disabled; idle games stay open");
+        }
+        tracing::info!(
+            "Timed challenges: timeout penalty {} points, deadline grace {} ms",
+            self.scoring.timeout_penalty,
+            self.scoring.deadline_grace_ms
+        );
+        tracing::info!(
+            "Review games: up to {} challenges, recency half-life {} days",
+            self.review.max_challenges,
+            self.review.recency_half_life_days
+        );
+        if self.analytics.token.is_none() {
+            tracing::warn!("ANALYTICS_TOKEN is not set; analytics exports will reject every request");
+        }
+    }
+}
+
+fn config_file_path() -> Option<PathBuf> {
+    match env::var("BIOGAMES_CONFIG") {
+        Ok(path) => Some(PathBuf::from(path)),
+        Err(_) => {
+            let default = PathBuf::from(DEFAULT_CONFIG_FILE);
+            default.exists().then_some(default)
+        }
+    }
+}
+
+/// Replaces `target` with the comma separated entries of `name`, if set.
+fn env_list(name: &str, target: &mut Vec<String>) {
+    if let Ok(raw) = env::var(name) {
+        *target = raw.split(',').map(str::to_string).collect();
+    }
+}
+
+fn normalized_list(values: &[String], normalize: impl Fn(&str) -> String) -> Vec<String> {
+    let mut normalized: Vec<String> = Vec::new();
+    for value in values.iter().map(|v| normalize(v.trim())).filter(|v| !v.is_empty()) {
+        if !normalized.contains(&value) {
+            normalized.push(value);
+        }
+    }
+    normalized
+}
+
+fn env_parsed<T>(name: &str, target: &mut T, problems: &mut Vec<String>)
+where
+    T: FromStr,
+    T::Err: Display
+{
+    if let Ok(raw) = env::var(name) {
+        match raw.trim().parse() {
+            Ok(value) => *target = value,
+            Err(e) => problems.push(format!("{} has invalid value {:?}: {}", name, raw, e)),
+        }
     }
-    
-    full_path
-} 
+}
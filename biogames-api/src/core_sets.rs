@@ -0,0 +1,145 @@
+use std::collections::BTreeSet;
+
+use chrono::Utc;
+use diesel::{dsl::count_star, insert_into, prelude::*};
+
+use crate::{
+    error::ApiError,
+    models::CoreSet,
+    schema::{core_set_members, core_sets, her2_cores}
+};
+
+pub fn find(connection: &mut PgConnection, name: &str) -> Result<CoreSet, ApiError> {
+    core_sets::table
+        .filter(core_sets::name.eq(name))
+        .select(CoreSet::as_select())
+        .first(connection)
+        .optional()?
+        .ok_or_else(|| ApiError::not_found("core_set_not_found", format!("No core set named '{}'", name)))
+}
+
+pub fn list(connection: &mut PgConnection) -> Result<Vec<(CoreSet, i64)>, ApiError> {
+    let sets = core_sets::table
+        .order(core_sets::name.asc())
+        .select(CoreSet::as_select())
+        .load::<CoreSet>(connection)?;
+    sets.into_iter()
+        .map(|set| {
+            let size = size(connection, set.id)?;
+            Ok((set, size))
+        })
+        .collect()
+}
+
+pub fn size(connection: &mut PgConnection, core_set_id: i32) -> Result<i64, ApiError> {
+    Ok(core_set_members::table
+        .filter(core_set_members::core_set_id.eq(core_set_id))
+        .select(count_star())
+        .get_result(connection)?)
+}
+
+pub fn member_ids(connection: &mut PgConnection, core_set_id: i32) -> Result<Vec<i32>, ApiError> {
+    Ok(core_set_members::table
+        .filter(core_set_members::core_set_id.eq(core_set_id))
+        .order(core_set_members::core_id.asc())
+        .select(core_set_members::core_id)
+        .load(connection)?)
+}
+
+pub fn contains(connection: &mut PgConnection, core_set_id: i32, core_id: i32) -> Result<bool, ApiError> {
+    Ok(diesel::select(diesel::dsl::exists(
+        core_set_members::table
+            .filter(core_set_members::core_set_id.eq(core_set_id))
+            .filter(core_set_members::core_id.eq(core_id))
+    ))
+    .get_result(connection)?)
+}
+
+/// Creates an unfrozen set with the given cores.
+pub fn create(
+    connection: &mut PgConnection,
+    name: &str,
+    description: Option<&str>,
+    core_ids: &[i32]
+) -> Result<CoreSet, ApiError> {
+    connection.transaction(|connection| {
+        let exists = diesel::select(diesel::dsl::exists(core_sets::table.filter(core_sets::name.eq(name))))
+            .get_result::<bool>(connection)?;
+        if exists {
+            return Err(ApiError::conflict("core_set_exists", format!("A core set named '{}' already exists", name)));
+        }
+
+        let set = insert_into(core_sets::table)
+            .values((core_sets::name.eq(name), core_sets::description.eq(description)))
+            .returning(CoreSet::as_returning())
+            .get_result::<CoreSet>(connection)?;
+        insert_members(connection, set.id, core_ids)?;
+        Ok(set)
+    })
+}
+
+/// Adds cores to an unfrozen set; cores already in it are ignored.
+pub fn add_members(connection: &mut PgConnection, name: &str, core_ids: &[i32]) -> Result<CoreSet, ApiError> {
+    connection.transaction(|connection| {
+        let set = lock(connection, name)?;
+        if set.frozen {
+            return Err(ApiError::conflict("core_set_frozen", format!("Core set '{}' is frozen", name)));
+        }
+        insert_members(connection, set.id, core_ids)?;
+        Ok(set)
+    })
+}
+
+/// Freezes a set so its members can never change again. Freezing an already
+/// frozen set is a no-op.
+pub fn freeze(connection: &mut PgConnection, name: &str) -> Result<CoreSet, ApiError> {
+    connection.transaction(|connection| {
+        let set = lock(connection, name)?;
+        if set.frozen {
+            return Ok(set);
+        }
+        if size(connection, set.id)? == 0 {
+            return Err(ApiError::validation("core_set_empty", format!("Core set '{}' has no cores", name)));
+        }
+        Ok(diesel::update(core_sets::table.find(set.id))
+            .set((core_sets::frozen.eq(true), core_sets::frozen_at.eq(Utc::now())))
+            .returning(CoreSet::as_returning())
+            .get_result(connection)?)
+    })
+}
+
+/// Reads the set and holds its row until the transaction ends, so it can't be
+/// frozen while members are being added.
+fn lock(connection: &mut PgConnection, name: &str) -> Result<CoreSet, ApiError> {
+    core_sets::table
+        .filter(core_sets::name.eq(name))
+        .select(CoreSet::as_select())
+        .for_update()
+        .first(connection)
+        .optional()?
+        .ok_or_else(|| ApiError::not_found("core_set_not_found", format!("No core set named '{}'", name)))
+}
+
+fn insert_members(connection: &mut PgConnection, core_set_id: i32, core_ids: &[i32]) -> Result<(), ApiError> {
+    let requested: BTreeSet<i32> = core_ids.iter().copied().collect();
+    let known: BTreeSet<i32> = her2_cores::table
+        .filter(her2_cores::id.eq_any(&requested))
+        .select(her2_cores::id)
+        .load::<i32>(connection)?
+        .into_iter()
+        .collect();
+    let unknown: Vec<String> = requested.difference(&known).map(ToString::to_string).collect();
+    if !unknown.is_empty() {
+        return Err(ApiError::validation("unknown_cores", format!("HER2 cores not found: {}", unknown.join(", "))));
+    }
+
+    let rows: Vec<_> = requested
+        .iter()
+        .map(|core_id| (core_set_members::core_set_id.eq(core_set_id), core_set_members::core_id.eq(*core_id)))
+        .collect();
+    insert_into(core_set_members::table)
+        .values(&rows)
+        .on_conflict_do_nothing()
+        .execute(connection)?;
+    Ok(())
+}
@@ -0,0 +1,93 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use axum::http::{HeaderName, HeaderValue, Method};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::{config::CorsConfig, request_id::X_REQUEST_ID};
+
+/// One entry of `cors.allowed_origins`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OriginRule {
+    /// `https://biogames.ucla.edu`: matches that origin only.
+    Exact(String),
+    /// `https://*.ucla.edu`: matches any subdomain (one or more labels) of
+    /// `ucla.edu` over the same scheme and port, but not `ucla.edu` itself.
+    Subdomain { prefix: String, suffix: String },
+}
+
+impl OriginRule {
+    /// Parses an already lowercased, trailing-slash-free origin. The error
+    /// completes the sentence "entry ... " in the config validation message.
+    pub fn parse(origin: &str) -> Result<OriginRule, &'static str> {
+        let (scheme, rest) = origin.split_once("://").ok_or("must start with http:// or https://")?;
+        if scheme != "http" && scheme != "https" {
+            return Err("must start with http:// or https://");
+        }
+        if rest.is_empty() || rest.contains(['/', '?', '#', '@']) {
+            return Err("must be scheme://host[:port] with no path");
+        }
+
+        match rest.strip_prefix("*.") {
+            Some(domain) => {
+                if domain.is_empty() || domain.contains('*') || !domain.contains('.') {
+                    return Err("may only use a wildcard as the first label of a multi-label domain");
+                }
+                Ok(OriginRule::Subdomain {
+                    prefix: format!("{}://", scheme),
+                    suffix: format!(".{}", domain),
+                })
+            }
+            None if rest.contains('*') => Err("may only use a wildcard as the first label of a multi-label domain"),
+            None => {
+                HeaderValue::from_str(origin).map_err(|_| "contains characters not allowed in an Origin header")?;
+                Ok(OriginRule::Exact(origin.to_string()))
+            }
+        }
+    }
+
+    pub fn matches(&self, origin: &str) -> bool {
+        match self {
+            OriginRule::Exact(allowed) => allowed == origin,
+            OriginRule::Subdomain { prefix, suffix } => {
+                let Some(labels) = origin.strip_prefix(prefix.as_str()).and_then(|o| o.strip_suffix(suffix.as_str())) else {
+                    return false;
+                };
+                !labels.is_empty()
+                    && labels.split('.').all(|label| {
+                        !label.is_empty() && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+                    })
+            }
+        }
+    }
+}
+
+/// CORS for the browser-facing routes. Analytics and health routes are mounted
+/// outside this layer, so browsers on other origins can never read them.
+/// Expects a config that passed `AppConfig::load` validation.
+pub fn cors_layer(config: &CorsConfig) -> CorsLayer {
+    let rules: Vec<OriginRule> = config.allowed_origins
+        .iter()
+        .filter_map(|origin| OriginRule::parse(origin).ok())
+        .collect();
+    let methods: Vec<Method> = config.allowed_methods
+        .iter()
+        .filter_map(|method| Method::from_str(method).ok())
+        .collect();
+    let headers: Vec<HeaderName> = config.allowed_headers
+        .iter()
+        .filter_map(|header| HeaderName::from_str(header).ok())
+        .collect();
+
+    CorsLayer::new()
+        .allow_origin(AllowOrigin::predicate(move |origin, _| {
+            origin.to_str().is_ok_and(|origin| {
+                let origin = origin.to_ascii_lowercase();
+                rules.iter().any(|rule| rule.matches(&origin))
+            })
+        }))
+        .allow_methods(methods)
+        .allow_headers(headers)
+        .expose_headers([X_REQUEST_ID.clone()])
+        .max_age(Duration::from_secs(config.max_age_secs))
+}
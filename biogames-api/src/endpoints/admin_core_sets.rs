@@ -0,0 +1,113 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    http::HeaderMap,
+    Json
+};
+
+use crate::{
+    run_db, PgPool,
+    auth::require_bearer,
+    config::AppConfig,
+    core_sets,
+    error::ApiError,
+    models::{AddCoreSetMembersRequest, CoreSet, CoreSetResponse, CoreSetSummaryResponse, CreateCoreSetRequest, ValidatedRequest}
+};
+
+fn core_set_response(connection: &mut diesel::PgConnection, set: CoreSet) -> Result<Json<CoreSetResponse>, ApiError> {
+    let core_ids = core_sets::member_ids(connection, set.id)?;
+    Ok(Json(CoreSetResponse {
+        name: set.name,
+        description: set.description,
+        frozen: set.frozen,
+        created_at: set.created_at,
+        frozen_at: set.frozen_at,
+        core_ids
+    }))
+}
+
+pub async fn list_core_sets(
+    State(pool): State<PgPool>,
+    State(config): State<Arc<AppConfig>>,
+    headers: HeaderMap
+) -> Result<Json<Vec<CoreSetSummaryResponse>>, ApiError> {
+    require_bearer(&headers, config.admin.token.as_deref(), "ADMIN_TOKEN")?;
+    run_db(&pool, move |connection| {
+        let sets = core_sets::list(connection)?;
+        Ok(Json(sets
+            .into_iter()
+            .map(|(set, size)| CoreSetSummaryResponse {
+                name: set.name,
+                description: set.description,
+                frozen: set.frozen,
+                created_at: set.created_at,
+                frozen_at: set.frozen_at,
+                size
+            })
+            .collect()))
+    })
+    .await
+}
+
+pub async fn get_core_set(
+    State(pool): State<PgPool>,
+    State(config): State<Arc<AppConfig>>,
+    headers: HeaderMap,
+    Path(name): Path<String>
+) -> Result<Json<CoreSetResponse>, ApiError> {
+    require_bearer(&headers, config.admin.token.as_deref(), "ADMIN_TOKEN")?;
+    run_db(&pool, move |connection| {
+        let set = core_sets::find(connection, &name)?;
+        core_set_response(connection, set)
+    })
+    .await
+}
+
+pub async fn create_core_set(
+    State(pool): State<PgPool>,
+    State(config): State<Arc<AppConfig>>,
+    headers: HeaderMap,
+    ValidatedRequest(body): ValidatedRequest<CreateCoreSetRequest>
+) -> Result<Json<CoreSetResponse>, ApiError> {
+    require_bearer(&headers, config.admin.token.as_deref(), "ADMIN_TOKEN")?;
+    run_db(&pool, move |connection| {
+        let set = core_sets::create(connection, &body.name, body.description.as_deref(), &body.core_ids)?;
+        tracing::info!("Created core set '{}' with {} core(s)", set.name, body.core_ids.len());
+        core_set_response(connection, set)
+    })
+    .await
+}
+
+pub async fn add_core_set_members(
+    State(pool): State<PgPool>,
+    State(config): State<Arc<AppConfig>>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    ValidatedRequest(body): ValidatedRequest<AddCoreSetMembersRequest>
+) -> Result<Json<CoreSetResponse>, ApiError> {
+    require_bearer(&headers, config.admin.token.as_deref(), "ADMIN_TOKEN")?;
+    run_db(&pool, move |connection| {
+        let set = core_sets::add_members(connection, &name, &body.core_ids)?;
+        tracing::info!("Added {} core(s) to core set '{}'", body.core_ids.len(), set.name);
+        core_set_response(connection, set)
+    })
+    .await
+}
+
+/// Freezing is one-way: once frozen, a set's members never change, so games
+/// drawn from it stay comparable.
+pub async fn freeze_core_set(
+    State(pool): State<PgPool>,
+    State(config): State<Arc<AppConfig>>,
+    headers: HeaderMap,
+    Path(name): Path<String>
+) -> Result<Json<CoreSetResponse>, ApiError> {
+    require_bearer(&headers, config.admin.token.as_deref(), "ADMIN_TOKEN")?;
+    run_db(&pool, move |connection| {
+        let set = core_sets::freeze(connection, &name)?;
+        tracing::info!("Core set '{}' is frozen", set.name);
+        core_set_response(connection, set)
+    })
+    .await
+}
@@ -0,0 +1,109 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::HeaderMap,
+    Json
+};
+use diesel::prelude::*;
+use serde::Deserialize;
+
+use crate::{
+    run_db, PgPool,
+    auth::require_bearer,
+    config::AppConfig,
+    core_sets,
+    error::ApiError,
+    models::{Game, SelectionAuditResponse},
+    protocol::{DrawMethod, Rotation},
+    sampling::Sampling,
+    schema::{challenges, games},
+    scoring::MAX_POINTS_PER_CHALLENGE,
+    selection::{select, DrawSpec}
+};
+
+#[derive(Deserialize)]
+pub struct SelectionAuditParams {
+    seed: Option<i64>,
+}
+
+/// Re-derives a game's challenges from its stored draw inputs, with its own
+/// seed or the one given, and compares them with what it was actually dealt.
+/// Review weights use the current `review.recency_half_life_days`. The game's
+/// length comes from `max_score`, which finishing a game early leaves alone.
+pub async fn audit_game_selection(
+    State(pool): State<PgPool>,
+    State(config): State<Arc<AppConfig>>,
+    headers: HeaderMap,
+    Path(game_id): Path<i32>,
+    Query(params): Query<SelectionAuditParams>
+) -> Result<Json<SelectionAuditResponse>, ApiError> {
+    require_bearer(&headers, config.admin.token.as_deref(), "ADMIN_TOKEN")?;
+    run_db(&pool, move |connection| {
+        let game = games::table
+            .find(game_id)
+            .select(Game::as_select())
+            .first::<Game>(connection)
+            .optional()?
+            .ok_or_else(|| ApiError::not_found("game_not_found", format!("No game with ID {}", game_id)))?;
+
+        let seed = params.seed.or(game.seed).ok_or_else(|| ApiError::conflict(
+            "game_not_seeded",
+            format!("Game {} was drawn before seeds were stored; pass ?seed= to derive a selection", game_id)
+        ))?;
+        let set_name = game.core_set.clone().ok_or_else(|| ApiError::conflict(
+            "game_without_core_set",
+            format!("Game {} has no recorded core set", game_id)
+        ))?;
+        let set = core_sets::find(connection, &set_name)?;
+
+        // Review games are the ones recorded without a sampling strategy.
+        let (draw, sampling) = match game.sampling.as_deref() {
+            Some(name) => {
+                let sampling = Sampling::parse(name, game.sampling_counts.as_deref()).ok_or_else(|| {
+                    ApiError::internal(format!("Game {} has invalid sampling '{}'", game_id, name))
+                })?;
+                (DrawMethod::Random, sampling)
+            }
+            None => (DrawMethod::ReviewMistakes, Sampling::Uniform),
+        };
+
+        let actual_core_ids = challenges::table
+            .filter(challenges::game_id.eq(game_id))
+            .order(challenges::id.asc())
+            .select(challenges::core_id)
+            .load::<i32>(connection)?;
+
+        let spec = DrawSpec {
+            draw,
+            core_set_id: set.id,
+            sampling,
+            total: game.max_score / MAX_POINTS_PER_CHALLENGE,
+            initial_core_id: game.initial_core_id,
+            user_id: &game.user_id,
+            game_id: game.id,
+            rotation: game.no_repeat.then_some(Rotation { reshow_missed_rate: game.reshow_missed_rate.unwrap_or(0.0) }),
+            as_of: game.started_at,
+            half_life_days: config.review.recency_half_life_days,
+        };
+        let selection = select(connection, &spec, seed)?;
+        let expected_core_ids: Vec<i32> = game.initial_core_id.into_iter().chain(selection.core_ids).collect();
+        let truncated = actual_core_ids.len() < expected_core_ids.len();
+
+        Ok(Json(SelectionAuditResponse {
+            game_id,
+            game_type: game.game_type,
+            core_set: set.name,
+            core_set_frozen: set.frozen,
+            sampling: game.sampling,
+            seed,
+            stored_seed: game.seed,
+            // Only finishing a game drops challenges.
+            matches: expected_core_ids.starts_with(&actual_core_ids) && (!truncated || game.finished_at.is_some()),
+            expected_core_ids,
+            actual_core_ids,
+            truncated
+        }))
+    })
+    .await
+}
@@ -1,36 +1,15 @@
 use axum::{
+    extract::State,
     http::{header, HeaderMap, StatusCode},
     response::{IntoResponse, Response},
 };
 use diesel::prelude::*;
 use diesel::sql_query;
-use diesel::sql_types::{Int4, Nullable, Text, Timestamp};
+use diesel::sql_types::{Bool, Float8, Int4, Int8, Nullable, Text, Timestamp};
 use serde::Serialize;
-use std::env;
+use std::sync::Arc;
 
-use crate::establish_db_connection;
-
-// -------------------------
-// Auth helper
-// -------------------------
-
-fn is_authorized(headers: &HeaderMap) -> bool {
-    let expected = match env::var("ANALYTICS_TOKEN") {
-        Ok(v) => v,
-        Err(_) => {
-            eprintln!("[analytics] ANALYTICS_TOKEN not set");
-            return false;
-        }
-    };
-
-    match headers.get(header::AUTHORIZATION) {
-        Some(value) => match value.to_str() {
-            Ok(s) => s == format!("Bearer {}", expected),
-            Err(_) => false,
-        },
-        None => false,
-    }
-}
+use crate::{auth::require_bearer, config::AppConfig, error::ApiError, run_db, PgPool};
 
 // -------------------------
 // Row structs
@@ -64,6 +43,36 @@ struct GamesRow {
 
     #[diesel(sql_type = Nullable<Text>)]
     user_id: Option<String>,
+
+    #[diesel(sql_type = Nullable<Text>)]
+    end_reason: Option<String>,
+
+    #[diesel(sql_type = Nullable<Int4>)]
+    time_limit_secs: Option<i32>,
+
+    #[diesel(sql_type = Nullable<Text>)]
+    core_set: Option<String>,
+
+    #[diesel(sql_type = Nullable<Text>)]
+    sampling: Option<String>,
+
+    #[diesel(sql_type = Nullable<Text>)]
+    sampling_counts: Option<String>,
+
+    #[diesel(sql_type = Nullable<Int8>)]
+    seed: Option<i64>,
+
+    #[diesel(sql_type = Nullable<Int4>)]
+    initial_core_id: Option<i32>,
+
+    #[diesel(sql_type = Nullable<Text>)]
+    test_form: Option<String>,
+
+    #[diesel(sql_type = Bool)]
+    no_repeat: bool,
+
+    #[diesel(sql_type = Nullable<Float8>)]
+    reshow_missed_rate: Option<f64>,
 }
 
 #[derive(QueryableByName, Debug, Serialize)]
@@ -85,6 +94,9 @@ struct ChallengesRow {
 
     #[diesel(sql_type = Nullable<Timestamp>)]
     submitted_at: Option<chrono::NaiveDateTime>,
+
+    #[diesel(sql_type = Bool)]
+    timed_out: bool,
 }
 
 #[derive(QueryableByName, Debug, Serialize)]
@@ -100,6 +112,12 @@ struct RegisteredUsersRow {
 
     #[diesel(sql_type = Nullable<Text>)]
     email: Option<String>,
+
+    #[diesel(sql_type = Nullable<Int4>)]
+    form_rotation: Option<i32>,
+
+    #[diesel(sql_type = Nullable<Text>)]
+    test_forms: Option<String>,
 }
 
 #[derive(QueryableByName, Debug, Serialize)]
@@ -119,20 +137,31 @@ struct EmailRegistryRow {
 // -------------------------
 
 const SQL_GAMES: &str = r#"
-SELECT id, username, started_at, finished_at, score, max_score, time_taken_ms, game_type, user_id
+SELECT id, username, started_at, finished_at, score, max_score, time_taken_ms, game_type, user_id, end_reason, time_limit_secs, core_set, sampling,
+       array_to_string(sampling_counts, ' ') AS sampling_counts, seed, initial_core_id, test_form, no_repeat, reshow_missed_rate
 FROM games
 ORDER BY id;
 "#;
 
 const SQL_CHALLENGES: &str = r#"
-SELECT id, game_id, core_id, guess, started_at, submitted_at
+SELECT id, game_id, core_id, guess, started_at, submitted_at, timed_out
 FROM challenges
 ORDER BY id;
 "#;
 
+// test_forms lists each form phase with the form assigned, e.g.
+// "pretest=A posttest=B".
 const SQL_REGISTERED_USERS: &str = r#"
-SELECT id, user_id, username, email
+SELECT id, user_id, username, email, forms.rotation AS form_rotation, forms.test_forms
 FROM registered_users
+LEFT JOIN LATERAL (
+    SELECT MIN(form_assignments.rotation) AS rotation,
+           string_agg(study_phases.game_type || '=' || test_forms.name, ' ' ORDER BY study_phases.position) AS test_forms
+    FROM form_assignments
+    JOIN study_phases ON study_phases.id = form_assignments.phase_id
+    JOIN test_forms ON test_forms.id = form_assignments.test_form_id
+    WHERE form_assignments.user_id = registered_users.user_id
+) AS forms ON TRUE
 ORDER BY id;
 "#;
 
@@ -146,23 +175,16 @@ ORDER BY id;
 // CSV helper
 // -------------------------
 
-fn csv_response<T: Serialize>(filename: &str, rows: Vec<T>) -> Response {
+fn csv_response<T: Serialize>(filename: &str, rows: Vec<T>) -> Result<Response, ApiError> {
     let mut wtr = csv::Writer::from_writer(Vec::new());
 
     for row in rows {
-        if let Err(e) = wtr.serialize(row) {
-            eprintln!("[analytics] csv serialize failed: {:?}", e);
-            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
-        }
+        wtr.serialize(row)
+            .map_err(|e| ApiError::internal(format!("[analytics] csv serialize failed: {:?}", e)))?;
     }
 
-    let data = match wtr.into_inner() {
-        Ok(d) => d,
-        Err(e) => {
-            eprintln!("[analytics] csv finalize failed: {:?}", e);
-            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
-        }
-    };
+    let data = wtr.into_inner()
+        .map_err(|e| ApiError::internal(format!("[analytics] csv finalize failed: {:?}", e)))?;
 
     let mut headers = HeaderMap::new();
     headers.insert(header::CONTENT_TYPE, "text/csv; charset=utf-8".parse().unwrap());
@@ -172,81 +194,65 @@ fn csv_response<T: Serialize>(filename: &str, rows: Vec<T>) -> Response {
         format!("attachment; filename=\"{}\"", filename).parse().unwrap(),
     );
 
-    (StatusCode::OK, headers, data).into_response()
+    Ok((StatusCode::OK, headers, data).into_response())
 }
 
 // -------------------------
 // Handlers (protected)
 // -------------------------
 
-pub async fn games_csv(headers: HeaderMap) -> Response {
-    if !is_authorized(&headers) {
-        return StatusCode::UNAUTHORIZED.into_response();
-    }
-
-    let conn = &mut establish_db_connection();
-
-    let rows: Vec<GamesRow> = match sql_query(SQL_GAMES).load(conn) {
-        Ok(r) => r,
-        Err(e) => {
-            eprintln!("[analytics] games query failed: {:?}", e);
-            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
-        }
-    };
-
-    csv_response("games.csv", rows)
+pub async fn games_csv(
+    State(pool): State<PgPool>,
+    State(config): State<Arc<AppConfig>>,
+    headers: HeaderMap
+) -> Result<Response, ApiError> {
+    require_bearer(&headers, config.analytics.token.as_deref(), "ANALYTICS_TOKEN")?;
+
+    run_db(&pool, move |conn| {
+        let rows: Vec<GamesRow> = sql_query(SQL_GAMES).load(conn)?;
+        csv_response("games.csv", rows)
+    })
+    .await
 }
 
-pub async fn challenges_csv(headers: HeaderMap) -> Response {
-    if !is_authorized(&headers) {
-        return StatusCode::UNAUTHORIZED.into_response();
-    }
-
-    let conn = &mut establish_db_connection();
-
-    let rows: Vec<ChallengesRow> = match sql_query(SQL_CHALLENGES).load(conn) {
-        Ok(r) => r,
-        Err(e) => {
-            eprintln!("[analytics] challenges query failed: {:?}", e);
-            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
-        }
-    };
-
-    csv_response("challenges.csv", rows)
+pub async fn challenges_csv(
+    State(pool): State<PgPool>,
+    State(config): State<Arc<AppConfig>>,
+    headers: HeaderMap
+) -> Result<Response, ApiError> {
+    require_bearer(&headers, config.analytics.token.as_deref(), "ANALYTICS_TOKEN")?;
+
+    run_db(&pool, move |conn| {
+        let rows: Vec<ChallengesRow> = sql_query(SQL_CHALLENGES).load(conn)?;
+        csv_response("challenges.csv", rows)
+    })
+    .await
 }
 
-pub async fn registered_users_csv(headers: HeaderMap) -> Response {
-    if !is_authorized(&headers) {
-        return StatusCode::UNAUTHORIZED.into_response();
-    }
-
-    let conn = &mut establish_db_connection();
-
-    let rows: Vec<RegisteredUsersRow> = match sql_query(SQL_REGISTERED_USERS).load(conn) {
-        Ok(r) => r,
-        Err(e) => {
-            eprintln!("[analytics] registered_users query failed: {:?}", e);
-            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
-        }
-    };
-
-    csv_response("registered_users.csv", rows)
+pub async fn registered_users_csv(
+    State(pool): State<PgPool>,
+    State(config): State<Arc<AppConfig>>,
+    headers: HeaderMap
+) -> Result<Response, ApiError> {
+    require_bearer(&headers, config.analytics.token.as_deref(), "ANALYTICS_TOKEN")?;
+
+    run_db(&pool, move |conn| {
+        let rows: Vec<RegisteredUsersRow> = sql_query(SQL_REGISTERED_USERS).load(conn)?;
+        csv_response("registered_users.csv", rows)
+    })
+    .await
 }
 
-pub async fn email_registry_csv(headers: HeaderMap) -> Response {
-    if !is_authorized(&headers) {
-        return StatusCode::UNAUTHORIZED.into_response();
-    }
-
-    let conn = &mut establish_db_connection();
-
-    let rows: Vec<EmailRegistryRow> = match sql_query(SQL_EMAIL_REGISTRY).load(conn) {
-        Ok(r) => r,
-        Err(e) => {
-            eprintln!("[analytics] email_registry query failed: {:?}", e);
-            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
-        }
-    };
-
-    csv_response("email_registry.csv", rows)
-}
\ No newline at end of file
+pub async fn email_registry_csv(
+    State(pool): State<PgPool>,
+    State(config): State<Arc<AppConfig>>,
+    headers: HeaderMap
+) -> Result<Response, ApiError> {
+    require_bearer(&headers, config.analytics.token.as_deref(), "ANALYTICS_TOKEN")?;
+
+    run_db(&pool, move |conn| {
+        let rows: Vec<EmailRegistryRow> = sql_query(SQL_EMAIL_REGISTRY).load(conn)?;
+        csv_response("email_registry.csv", rows)
+    })
+    .await
+}
@@ -1,67 +1,38 @@
-use axum::{extract::Path, http::StatusCode, response::IntoResponse, Json};
-use diesel::prelude::*;
+use axum::{extract::{Path, State}, Json};
 
 use crate::{
-    establish_db_connection,
-    models::GameCountResponse,
-    schema::games
+    run_db, PgPool,
+    error::ApiError,
+    telemetry::record_user_id,
+    models::{GameCountResponse, GameMode},
+    progression::Progress
 };
 
-pub async fn check_game_type(Path(user_id): Path<String>) -> impl IntoResponse {
-    let connection = &mut establish_db_connection();
+pub async fn check_game_type(
+    State(pool): State<PgPool>,
+    Path(user_id): Path<String>
+) -> Result<Json<GameCountResponse>, ApiError> {
+    record_user_id(&user_id);
+    run_db(&pool, move |connection| {
+        tracing::info!("Checking game type for user_id: {}", user_id);
+        let progress = Progress::load(connection, &user_id)?;
 
-    tracing::info!("Checking game type for user_id: {}", user_id);
-    let game_type = "pretest";
-    let game_count_pretest = games::table
-        .filter(games::user_id.eq(user_id.clone()))
-        .filter(games::game_type.eq(game_type))
-        .count()
-        .get_result::<i64>(connection);
+        let game_counts = GameCountResponse {
+            pretest: progress.played(GameMode::Pretest),
+            posttest: progress.played(GameMode::Posttest),
+            training: progress.played(GameMode::Training),
+            available: progress.available()
+        };
 
-    let game_type = "posttest";
-    let game_count_posttest = games::table
-        .filter(games::user_id.eq(user_id.clone()))
-        .filter(games::game_type.eq(game_type))
-        .count()
-        .get_result::<i64>(connection);
-
-    let game_type = "training";
-    let game_count_training = games::table
-        .filter(games::user_id.eq(user_id.clone()))
-        .filter(games::game_type.eq(game_type))
-        .count()
-        .get_result::<i64>(connection); // get_result returns Result<i64, diesel::result::Error>
-    
-    let result = game_count_pretest.and_then(|pretest| {
-        game_count_posttest.and_then(|posttest| {
-            game_count_training.and_then(|training| {
-                Ok(GameCountResponse {
-                    pretest: pretest,
-                    posttest: posttest,
-                    training: training
-                })
-            })
-        })
-    });
-
-    match result {
-        Ok(game_counts) => {
-            tracing::info!(
-                "Game count for {}: pretest: {}, posttest: {}, training: {}",
-                user_id,
-                game_counts.pretest,
-                game_counts.posttest,
-                game_counts.training
-            );
-            Json(game_counts).into_response()
-        }
-        Err(e) => {
-            tracing::error!("Error retrieving game counts: {:?}", e);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Internal Server Error",
-            )
-                .into_response()
-        }
-    }
+        tracing::info!(
+            "Game count for {}: pretest: {}, posttest: {}, training: {}, available: {:?}",
+            user_id,
+            game_counts.pretest,
+            game_counts.posttest,
+            game_counts.training,
+            game_counts.available
+        );
+        Ok(Json(game_counts))
+    })
+    .await
 }
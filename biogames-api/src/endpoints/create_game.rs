@@ -1,372 +1,322 @@
-use axum::{http::StatusCode, response::IntoResponse};
-use diesel::{insert_into, sql_query, sql_types::Integer, ExpressionMethods, RunQueryDsl, QueryDsl};
+use diesel::{
+    insert_into, Connection, ExpressionMethods, PgConnection, RunQueryDsl,
+    QueryDsl
+};
 use diesel::BoolExpressionMethods;
+use std::sync::Arc;
+
 use tracing::{event, Level};
-use axum::extract::Query;
-use once_cell::sync::Lazy;
+use axum::extract::{Query, State};
 use serde::Deserialize;
 use axum::Json;
-use crate::schema::registered_users::dsl as rudsl;
 use crate::schema::challenges::dsl as ccdsl;
-use serde_json::json;
 
 // use diesel::result::Error;
 // use tracing::{debug, error};
 
 use crate::{
-    establish_db_connection,
-    models::{Challenge, CreateGameRequest, Game, GameResponse, ValidatedRequest},
+    run_db, PgPool,
+    config::AppConfig,
+    core_sets,
+    error::ApiError,
+    games::challenge_progress,
+    progression::Progress,
+    rate_limit::RateLimiter,
+    telemetry::{record_game_id, record_user_id},
+    test_forms::assigned_form,
+    models::{Challenge, CreateGameRequest, Game, GameMode, GameResponse, ValidatedRequest},
+    protocol::{lock_user_games, DrawMethod},
+    sampling::Sampling,
+    selection::{fixed_order, new_seed, select, DrawSpec},
+    scoring::MAX_POINTS_PER_CHALLENGE,
     schema::games::dsl::*
 };
 
-static TEST_IMAGE_IDS: Lazy<Vec<i32>> = Lazy::new(|| {
-    vec![345,
-    20125,
-    23246,
-    6134,
-    9192,
-    4376,
-    1162,
-    22787,
-    9809,
-    19324,
-    2907,
-    14342,
-    14795,
-    438,
-    12330,
-    10186,
-    8781,
-    12076,
-    19052,
-    6547,
-    5077,
-    8050,
-    9934,
-    23774,
-    10636,
-    13660,
-    20394,
-    18529,
-    19444,
-    4625,
-    19430,
-    23853,
-    210,
-    16056,
-    5231,
-    940,
-    8939,
-    22438,
-    12988,
-    15627,
-    3138,
-    18219,
-    18021,
-    19185,
-    22208,
-    22696,
-    15629,
-    9052,
-    23770,
-    18238]
-});
-
-
 #[derive(Deserialize)]
 pub struct GameParams {
     mode: Option<String>,
 }
 
 pub async fn create_game(
+    State(pool): State<PgPool>,
+    State(limiter): State<RateLimiter>,
+    State(config): State<Arc<AppConfig>>,
     Query(params): Query<GameParams>,
     ValidatedRequest(body): ValidatedRequest<CreateGameRequest>
-) -> impl IntoResponse {
-    let connection = &mut establish_db_connection();
-
-    use crate::schema::games::dsl as gdsl;
-
-    // Lookup display-username from registered_users by user_id (now body.user_id)
-    let real_username: String = match rudsl::registered_users
-        .filter(rudsl::user_id.eq(&body.user_id))
-        .select(rudsl::username)
-        .first::<Option<String>>(connection)
-    {
-        Ok(db_username_value_option) => {
-            if let Some(name_str) = db_username_value_option {
-                name_str
-            } else {
-                // The user_id was found, but their username column in the DB is NULL.
-                return (
-                    StatusCode::BAD_REQUEST,
-                    format!("User ID '{}' was found, but no username is set for it. Please register a username.", body.user_id),
-                )
-                .into_response();
-            }
-        },
-        Err(diesel::NotFound) => { // No user record found for the given body.user_id
-            return (
-                StatusCode::BAD_REQUEST,
-                format!("No registered user found for User ID '{}'", body.user_id),
-            )
-            .into_response();
-        }
-        Err(e) => { // Some other database error occurred
-            event!(Level::ERROR, "Database error when fetching username for User ID {}: {:?}", body.user_id, e);
-            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to retrieve user data due to a database error.").into_response();
-        }
-    };
-
-    let pretest_condition = user_id.eq(&body.user_id).and(game_type.eq("pretest"));
-    let pretest_count: i64 = gdsl::games
-        .filter(pretest_condition)
-        .count()
-        .get_result(connection)
-        .unwrap_or(0);
-
-    let training_condition = user_id.eq(&body.user_id).and(game_type.eq("training"));
-    let training_count: i64 = gdsl::games
-        .filter(training_condition)
-        .count()
-        .get_result(connection)
-        .unwrap_or(0);
-
-    let posttest_condition = user_id.eq(&body.user_id).and(game_type.eq("posttest"));
-    let posttest_count: i64 = gdsl::games
-        .filter(posttest_condition)
-        .count()
-        .get_result(connection)
-        .unwrap_or(0);
-
-    let requested_mode = params.mode.as_deref().unwrap_or("training");
-
-    let allowed = match requested_mode {
-        "pretest" => pretest_count == 0,
-        "training" => pretest_count > 0 && training_count < 5,
-        "posttest" => pretest_count > 0 && training_count >= 5 && posttest_count == 0,
-        _ => true,
+) -> Result<Json<GameResponse>, ApiError> {
+    record_user_id(&body.user_id);
+    let requested_mode = match params.mode.as_deref() {
+        Some(mode) => mode.parse::<GameMode>().map_err(|e| ApiError::validation("unknown_mode", e.to_string()))?,
+        None => GameMode::Training,
     };
+    limiter.check_user(&body.user_id)?;
+    run_db(&pool, move |connection| {
+        use crate::schema::games::dsl as gdsl;
+
+        // Everything from reading the user's progress to inserting the new
+        // game runs in one transaction under a per-user lock. Concurrent
+        // requests (double clicks, client retries) queue up behind it, see the
+        // committed game, and get it back instead of creating a second one.
+        // Any error rolls back the game row too, so a game with missing
+        // challenges is never visible to get_current_challenge.
+        connection.transaction::<_, ApiError, _>(|connection| {
+            lock_user_games(connection, &body.user_id)?;
+
+            let progress = Progress::load(connection, &body.user_id)?;
+            let real_username = progress.username.clone().ok_or_else(|| ApiError::validation(
+                "username_not_set",
+                format!("User ID '{}' was found, but no username is set for it. Please register a username.", body.user_id)
+            ))?;
+
+            let (phase, phase_progress) = progress.phase(requested_mode).ok_or_else(|| ApiError::validation(
+                "mode_not_in_protocol",
+                format!("Mode '{}' is not part of the {} study protocol", requested_mode, progress.protocol_name)
+            ))?;
+
+            // A game interrupted by a refresh or crash is picked up where it
+            // was left rather than orphaned (and counted against the limit).
+            if phase.resume_unfinished {
+                if let Some(unfinished_id) = phase_progress.unfinished_game_id {
+                    tracing::debug!("Resuming unfinished {} game {} for user {}", requested_mode, unfinished_id, body.user_id);
+                    let unfinished = gdsl::games.find(unfinished_id).first::<Game>(connection)?;
+                    return existing_game_response(connection, unfinished);
+                }
+            }
 
-    if !allowed {
-        tracing::debug!(
-            "Game creation denied - User ID: {}, Mode: {}, Counts - Pretest: {}, Training: {}, Posttest: {}",
-            body.user_id,
-            requested_mode,
-            pretest_count,
-            training_count,
-            posttest_count
-        );
-
-        if (requested_mode == "pretest" && pretest_count > 0) || (requested_mode == "posttest" && posttest_count > 0) {
-            let existing_game_result = gdsl::games
-                .filter(gdsl::user_id.eq(&body.user_id)
-                .and(gdsl::game_type.eq(requested_mode)))
-                .order(gdsl::id.desc())
-                .first::<Game>(connection);
-
-            match existing_game_result {
-                // newly added to account for the case when the user is partway through
-                // their pretest (pretest_count == 1) but have not completed it yet
-                // due to a page refresh, crash, etc.
-                Ok(existing_game) => {
-                    tracing::debug!(
-                        "Resuming existing {} game {} for user {}",
-                        requested_mode,
-                        existing_game.id,
-                        body.user_id
-                    );
-
-                    return Json(GameResponse {
-                        id: existing_game.id,
-                        user: existing_game.username.clone(),
-                        results: None,
-                        total_points: None,
-                    }).into_response();
+            if !phase_progress.prerequisites_met || phase_progress.limit_reached {
+                tracing::debug!(
+                    "Game creation denied - User ID: {}, Mode: {}, Reasons: {:?}",
+                    body.user_id,
+                    requested_mode,
+                    phase_progress.reasons
+                );
+
+                if phase.is_single_attempt() && phase_progress.played > 0 {
+                    let existing_game_result = gdsl::games
+                        .filter(gdsl::user_id.eq(&body.user_id)
+                        .and(gdsl::game_type.eq(requested_mode)))
+                        .order(gdsl::id.desc())
+                        .first::<Game>(connection);
+
+                    match existing_game_result {
+                        // The user's one game of this phase, finished or not, is
+                        // returned again so repeated requests are idempotent.
+                        Ok(existing_game) => {
+                            tracing::debug!(
+                                "Returning existing {} game {} for user {}",
+                                requested_mode,
+                                existing_game.id,
+                                body.user_id
+                            );
+                            return existing_game_response(connection, existing_game);
+                        }
+                        Err(e) => {
+                            event!(
+                                Level::ERROR,
+                                "Expected existing {} game for user {} but failed to fetch it: {:?}",
+                                requested_mode,
+                                body.user_id,
+                                e
+                            );
+                        }
+                    }
                 }
-                Err(e) => {
-                    event!(
-                        Level::ERROR,
-                        "Expected existing {} game for user {} but failed to fetch it: {:?}",
-                        requested_mode,
-                        body.user_id,
-                        e
-                    );
 
-                    return (
-                        StatusCode::BAD_REQUEST,
-                        Json(json!({
-                            "error": format!("Game limit reached or prerequisites not met for {} mode", requested_mode),
-                            "message": format!("Game limit reached or prerequisites not met for {} mode", requested_mode)
-                        }))
-                    ).into_response();
+                if !phase_progress.prerequisites_met {
+                    return Err(ApiError::forbidden(
+                        "prerequisites_not_met",
+                        format!("Cannot start {} mode yet: {}", requested_mode, phase_progress.reasons.join("; "))
+                    ));
                 }
+                return Err(ApiError::conflict(
+                    "game_limit_reached",
+                    format!("Game limit reached for {} mode", requested_mode)
+                ));
             }
-        }
-
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(json!({
-                "error": format!("Game limit reached or prerequisites not met for {} mode", requested_mode),
-                "message": format!("Game limit reached or prerequisites not met for {} mode", requested_mode)
-            }))
-        ).into_response();
-    }
-
-    // log requested mode
-    
-    let is_test = requested_mode == "posttest" || requested_mode == "pretest";
-    let mode = requested_mode.to_string();
-    
-    let mut challenges_per_game = if is_test { 50 } else { 20 };
-
-    {
-        tracing::debug!("Creating game for user_id: {}, game mode: {}", body.user_id, params.mode.as_deref().unwrap_or("uhoh"));
-    }
-
-    // create game
-    let game = insert_into(games)
-        .values((
-            user_id.eq(body.user_id.clone()), 
-            username.eq(real_username.clone()),  // Add the username!
-            max_score.eq(challenges_per_game * 5), 
-            game_type.eq(&mode)
-        ))
-        .get_result::<Game>(connection).unwrap();
-
-    let mut final_challenges: Vec<Challenge> = Vec::new();
 
-    if let Some(initial_core_id) = body.initial_her2_core_id {
-        if challenges_per_game == 0 { // Should not happen with current numbers, but good check
-            event!(Level::ERROR, "challenges_per_game is 0, cannot insert initial challenge for game {}", game.id);
-            // Consider deleting the game record here if no challenges can be added
-            return (StatusCode::INTERNAL_SERVER_ERROR, "Cannot create a game with zero challenges").into_response();
-        }
-
-        // Insert the initial challenge directly
-        match diesel::insert_into(ccdsl::challenges)
-            .values((ccdsl::game_id.eq(game.id), ccdsl::core_id.eq(initial_core_id)))
-            .get_result::<Challenge>(connection) 
-        {
-            Ok(initial_challenge) => {
-                final_challenges.push(initial_challenge);
-                challenges_per_game -= 1; // Decrement count for remaining challenges
-            }
-            Err(e) => {
-                event!(Level::ERROR, "Failed to insert initial challenge with core_id {}: {:?} for game {}", initial_core_id, e, game.id);
-                // Rollback game creation or handle error appropriately
-                // For now, delete the created game record
-                let _ = diesel::delete(games.filter(id.eq(game.id))).execute(connection);
-                return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to set initial challenge").into_response();
+            if !phase_progress.window_open {
+                return Err(ApiError::forbidden(
+                    "phase_not_open",
+                    format!("{} mode is not open: {}", requested_mode, phase_progress.reasons.join("; "))
+                ));
             }
-        }
-    }
 
-    if challenges_per_game > 0 { // If we still need to fetch more challenges
-        let query_remaining = if is_test {
-            // For test modes, select from TEST_IMAGE_IDS, excluding initial_core_id if it was one of them
-            // AND id != $INITIAL_ID (if initial_core_id was provided and is in TEST_IMAGE_IDS, this is implicitly handled by not re-selecting it)
-            // More robustly, explicitly exclude it:
-            if body.initial_her2_core_id.is_some() {
-                 r#"
-                INSERT INTO challenges (game_id, core_id)
-                SELECT $1, id FROM her2_cores
-                WHERE id = ANY($3) AND id != $4 
-                ORDER BY random()
-                LIMIT $2
-                RETURNING *
-                "#
-            } else { // No initial_core_id, select all from TEST_IMAGE_IDS
-                 r#"
-                INSERT INTO challenges (game_id, core_id)
-                SELECT $1, id FROM her2_cores
-                WHERE id = ANY($3)
-                ORDER BY random()
-                LIMIT $2
-                RETURNING *
-                "#
-            }
-        } else {
-            // For training mode, select NOT from TEST_IMAGE_IDS, excluding initial_core_id
-            if body.initial_her2_core_id.is_some() {
-                r#"
-                INSERT INTO challenges (game_id, core_id)
-                SELECT $1, id FROM her2_cores
-                WHERE id != ALL($3) AND id != $4 
-                ORDER BY random()
-                LIMIT $2
-                RETURNING *
-                "#
-            } else { // No initial_core_id, select all not in TEST_IMAGE_IDS
-                r#"
-                INSERT INTO challenges (game_id, core_id)
-                SELECT $1, id FROM her2_cores
-                WHERE id != ALL($3)
-                ORDER BY random()
-                LIMIT $2
-                RETURNING *
-                "#
+            let challenges_per_game = match phase.draw {
+                DrawMethod::ReviewMistakes => phase.challenges_per_game.min(config.review.max_challenges),
+                DrawMethod::Random => phase.challenges_per_game,
+            };
+            // Form phases draw from the participant's assigned parallel form.
+            let form = if phase.uses_test_form {
+                Some(assigned_form(connection, &progress.protocol, &body.user_id, phase)?)
+            } else {
+                None
+            };
+            let set = core_sets::find(connection, form.map_or(&phase.core_set, |form| &form.core_set))?;
+            let game_seed = phase.fixed_seed.unwrap_or_else(new_seed);
+            let (game_sampling, game_rotation) = match phase.draw {
+                DrawMethod::Random => (Some(phase.sampling), phase.rotation),
+                DrawMethod::ReviewMistakes => (None, None),
+            };
+
+            // The preview core the player already saw becomes the first
+            // challenge, except in review games, which hold only mistakes,
+            // fixed-order phases, whose sequence includes the first core, and
+            // form phases, whose form the preview can't know.
+            let initial_core = body.initial_her2_core_id
+                .filter(|_| phase.draw == DrawMethod::Random && phase.fixed_seed.is_none() && !phase.uses_test_form);
+            if let Some(initial) = initial_core {
+                if !core_sets::contains(connection, set.id, initial)? {
+                    return Err(ApiError::validation(
+                        "initial_core_not_in_set",
+                        format!("HER2 core {} is not in the {} core set", initial, set.name)
+                    ));
+                }
             }
-        };
 
-        tracing::debug!("Creating {} remaining challenges for game: {}", challenges_per_game, game.id);
-        tracing::debug!("Query for remaining: {}", query_remaining);
-
-        let remaining_challenges_result = if let Some(initial_id) = body.initial_her2_core_id {
-             sql_query(query_remaining)
-                .bind::<Integer, _>(game.id)
-                .bind::<Integer, _>(challenges_per_game) // Use updated count
-                .bind::<diesel::sql_types::Array<Integer>, _>(&*TEST_IMAGE_IDS)
-                .bind::<Integer, _>(initial_id) // Bind the initial_id to exclude
-                .get_results::<Challenge>(connection)
-        } else {
-             sql_query(query_remaining)
-                .bind::<Integer, _>(game.id)
-                .bind::<Integer, _>(challenges_per_game)
-                .bind::<diesel::sql_types::Array<Integer>, _>(&*TEST_IMAGE_IDS)
-                .get_results::<Challenge>(connection)
-        };
-
-        match remaining_challenges_result {
-            Ok(mut fetched_challenges) => {
-                final_challenges.append(&mut fetched_challenges);
-            }
-            Err(e) => {
-                event!(Level::ERROR, "Error fetching remaining challenges for game {}: {:?}", game.id, e);
-                 // If initial challenge was inserted, it's still there.
-                 // If no challenges at all, game might be invalid.
-                if final_challenges.is_empty() {
-                    let _ = diesel::delete(games.filter(id.eq(game.id))).execute(connection);
-                    return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to fetch any challenges for the game").into_response();
+            tracing::debug!("Creating game for user_id: {}, game mode: {}", body.user_id, requested_mode);
+
+            let game = insert_into(games)
+                .values((
+                    user_id.eq(body.user_id.clone()),
+                    username.eq(real_username.clone()),
+                    max_score.eq(challenges_per_game * MAX_POINTS_PER_CHALLENGE),
+                    game_type.eq(requested_mode),
+                    time_limit_secs.eq(phase.time_limit_secs),
+                    core_set.eq(&set.name),
+                    sampling.eq(game_sampling.map(|strategy| strategy.as_str())),
+                    sampling_counts.eq(match game_sampling {
+                        Some(Sampling::Explicit(counts)) => Some(counts.to_vec()),
+                        _ => None,
+                    }),
+                    seed.eq(game_seed),
+                    initial_core_id.eq(initial_core),
+                    test_form.eq(form.map(|form| form.name.as_str())),
+                    no_repeat.eq(game_rotation.is_some()),
+                    reshow_missed_rate.eq(game_rotation.map(|rotation| rotation.reshow_missed_rate))
+                ))
+                .get_result::<Game>(connection)?;
+            record_game_id(game.id);
+
+            // Fixed-seed phases deal everyone the same order, whose first
+            // core is what the preview shows.
+            let selection = match phase.fixed_seed.filter(|_| phase.draw == DrawMethod::Random) {
+                Some(fixed_seed) => fixed_order(connection, set.id, phase.sampling, challenges_per_game, fixed_seed)?,
+                None => {
+                    let spec = DrawSpec {
+                        draw: phase.draw,
+                        core_set_id: set.id,
+                        sampling: phase.sampling,
+                        total: challenges_per_game,
+                        initial_core_id: initial_core,
+                        user_id: &body.user_id,
+                        game_id: game.id,
+                        rotation: game_rotation,
+                        as_of: game.started_at,
+                        half_life_days: config.review.recency_half_life_days,
+                    };
+                    select(connection, &spec, game_seed)?
                 }
+            };
+            if selection.topped_up {
+                tracing::warn!(
+                    "Core set {} is short of some score classes for {} sampling; filled a {} game from the others",
+                    set.name,
+                    phase.sampling.as_str(),
+                    requested_mode
+                );
+                metrics::counter!("biogames_sampling_topped_up_total", "sampling" => phase.sampling.as_str()).increment(1);
             }
-        }
-    }
-    
-    // Replace the old `challenges` variable with `final_challenges`
-    let challenges = final_challenges;
 
-    tracing::debug!("Total number of challenges for game {}: {}", game.id, challenges.len());
+            if selection.recycled > 0 {
+                tracing::info!(
+                    "User {} has been served nearly all of core set {}; recycling {} core(s) in game {}",
+                    body.user_id,
+                    set.name,
+                    selection.recycled,
+                    game.id
+                );
+            }
 
-    if challenges.is_empty() {
-        event!(Level::ERROR, "no HER2 cores found for game {}", game.id);
+            tracing::debug!(
+                "Creating {} challenges for game {} with seed {} ({} re-shown, {} recycled)",
+                selection.core_ids.len(),
+                game.id,
+                game_seed,
+                selection.reshown,
+                selection.recycled
+            );
+
+            let rows: Vec<_> = initial_core
+                .into_iter()
+                .chain(selection.core_ids)
+                .map(|drawn_core_id| (ccdsl::game_id.eq(game.id), ccdsl::core_id.eq(drawn_core_id)))
+                .collect();
+            let challenges = if rows.is_empty() {
+                Vec::new()
+            } else {
+                insert_into(ccdsl::challenges).values(&rows).get_results::<Challenge>(connection)?
+            };
+
+            let total = challenges.len();
+            if phase.draw == DrawMethod::ReviewMistakes {
+                // A player with fewer mistakes than the cap gets a shorter game
+                if total == 0 {
+                    return Err(ApiError::conflict(
+                        "nothing_to_review",
+                        "No mis-scored training cores to review yet"
+                    ));
+                }
+                if total < challenges_per_game as usize {
+                    diesel::update(games.find(game.id))
+                        .set(max_score.eq(total as i32 * MAX_POINTS_PER_CHALLENGE))
+                        .execute(connection)?;
+                }
+            } else if total < challenges_per_game as usize {
+                event!(
+                    Level::ERROR,
+                    "Only {} of {} HER2 cores available in core set {} for a {} game; not creating it",
+                    total,
+                    challenges_per_game,
+                    set.name,
+                    requested_mode
+                );
+                return Err(ApiError::internal(format!(
+                    "Not enough HER2 cores in core set {} for a {} game ({} of {})",
+                    set.name,
+                    requested_mode,
+                    total,
+                    challenges_per_game
+                )));
+            }
 
-        diesel::delete(
-            diesel::QueryDsl::filter(gdsl::games, gdsl::id.eq(game.id))
-        )
-            .execute(connection)
-            .unwrap();
+            tracing::debug!("Total number of challenges for game {}: {}", game.id, challenges.len());
 
-        return (StatusCode::INTERNAL_SERVER_ERROR, "No HER2 cores found").into_response();
-    }
+            metrics::counter!("biogames_games_created_total", "game_type" => game.game_type.as_str()).increment(1);
 
-    if challenges.len() < 20 {
-        event!(Level::WARN, "less than 20 HER2 cores available ({} found)", challenges.len());
-    }
+            Ok(Json(GameResponse {
+                id: game.id,
+                user: game.username,
+                results: None,
+                total_points: None,
+                completed_challenges: Some(0),
+                total_challenges: Some(challenges.len() as i32),
+                resumed: Some(false)
+            }))
+        })
+    })
+    .await
+}
 
-    Json(GameResponse {
+fn existing_game_response(connection: &mut PgConnection, game: Game) -> Result<Json<GameResponse>, ApiError> {
+    record_game_id(game.id);
+    let progress = challenge_progress(connection, game.id)?;
+    Ok(Json(GameResponse {
         id: game.id,
         user: game.username,
         results: None,
-        total_points: None
-    }).into_response()
+        total_points: None,
+        completed_challenges: Some(progress.completed),
+        total_challenges: Some(progress.total),
+        resumed: Some(true)
+    }))
 }
@@ -1,4 +1,5 @@
 use axum::{
+    extract::State,
     response::IntoResponse,
     Json,
 };
@@ -9,8 +10,9 @@ use serde::{Deserialize, Serialize};
 use validator::Validate;
 use std::process::{Command, Stdio};
 use std::io::Write;
+use std::sync::Arc;
 
-use crate::establish_db_connection;
+use crate::{config::AppConfig, error::ApiError, run_db, telemetry::record_user_id, PgPool};
 use crate::models::{ValidatedRequest, NewRegisteredUser};
 use crate::schema::registered_users;
 use diesel::insert_into;
@@ -208,126 +210,132 @@ entitled.
         user_id = user_id,
     );
 
-    if let Ok(mut child) = Command::new("sendmail")
+    // The address itself is never logged, only the user id it was sent for.
+    match Command::new("sendmail")
         .arg("-t")
         .stdin(Stdio::piped())
         .spawn()
     {
-        if let Some(mut stdin) = child.stdin.take() {
-            let _ = stdin.write_all(email_body.as_bytes());
+        Ok(mut child) => {
+            if let Some(mut stdin) = child.stdin.take() {
+                if let Err(e) = stdin.write_all(email_body.as_bytes()) {
+                    tracing::error!(user_id, "Failed to write user ID email to sendmail: {}", e);
+                }
+            }
+            match child.wait() {
+                Ok(status) if status.success() => tracing::info!(user_id, "User ID email handed to sendmail"),
+                Ok(status) => tracing::error!(user_id, "sendmail exited with {}", status),
+                Err(e) => tracing::error!(user_id, "Failed to wait for sendmail: {}", e),
+            }
         }
-        let _ = child.wait();
-    } else {
-        eprintln!("Failed to send email to {}", email);
+        Err(e) => tracing::error!(user_id, "Failed to start sendmail: {}", e),
     }
 }
 
 pub async fn generate_user_id(
+    State(pool): State<PgPool>,
+    State(config): State<Arc<AppConfig>>,
     ValidatedRequest(payload): ValidatedRequest<GenerateUserIdRequest>,
-) -> impl IntoResponse {
-    let connection = &mut establish_db_connection();
+) -> Result<GenerateUserIdResponse, ApiError> {
+    // Only configured domains become label values; anything else is "other".
+    let domain_label = config.allowed_email_domain(&payload.email)
+        .map(|domain| domain.trim_start_matches('@').to_string())
+        .unwrap_or_else(|| "other".to_string());
 
+    let result = register_email(&pool, &config, payload).await;
+
+    let outcome = match &result {
+        Ok(_) => "success",
+        Err(e) => e.code(),
+    };
+    metrics::counter!("biogames_registration_attempts_total", "domain" => domain_label, "outcome" => outcome).increment(1);
+    result
+}
 
+async fn register_email(
+    pool: &PgPool,
+    config: &AppConfig,
+    payload: GenerateUserIdRequest
+) -> Result<GenerateUserIdResponse, ApiError> {
     // Validate that it's an allowed email
-    if !payload.email.ends_with("@ucla.edu") && !payload.email.ends_with("@mednet.ucla.edu") && !payload.email.ends_with("@mail.huji.ac.il") && !payload.email.ends_with("@hadassah.org.il") {
-        return GenerateUserIdResponse {
-            success: false,
-            user_id: String::new(),
-            message: "Only UCLA email addresses are allowed".to_string(),
-        }.into_response();
+    if !config.is_allowed_email(&payload.email) {
+        return Err(ApiError::forbidden("email_domain_not_allowed", "Only UCLA email addresses are allowed"));
     }
 
-    // Check if the email is used already (only if email reuse is not allowed)
-    #[cfg(not(feature = "allow_email_reuse"))]
-    {
-        let email_hash = hash_email(&payload.email);
-
-        let email_exists = diesel::dsl::select(diesel::dsl::exists(
-            email_registry::table.filter(email_registry::email_hash.eq(email_hash.clone()))
-        )).get_result::<bool>(connection).unwrap_or(false);
-
-        if email_exists {
-            return GenerateUserIdResponse {
-                success: false,
-                user_id: String::new(),
-                message: "This email address has already been used! Please use your existing user_id or contact an admin if you don't have it.".to_string()
-            }.into_response();
+    run_db(pool, move |connection| {
+        // Check if the email is used already (only if email reuse is not allowed)
+        #[cfg(not(feature = "allow_email_reuse"))]
+        {
+            let email_hash = hash_email(&payload.email);
+
+            let email_exists = diesel::dsl::select(diesel::dsl::exists(
+                email_registry::table.filter(email_registry::email_hash.eq(email_hash.clone()))
+            )).get_result::<bool>(connection)?;
+
+            if email_exists {
+                return Err(ApiError::conflict(
+                    "email_already_registered",
+                    "This email address has already been used! Please use your existing user_id or contact an admin if you don't have it."
+                ));
+            }
         }
-    }
 
-    // Generate a unique user ID
-    let user_id = loop {
-        // Select a random word
-        let word = WORDS.choose(&mut thread_rng()).unwrap();
+        // Generate a unique user ID
+        let user_id = loop {
+            // Select a random word
+            let word = WORDS.choose(&mut thread_rng()).unwrap();
         
-        // Generate 2 random digits
-        let digits: String = (0..2)
-            .map(|_| thread_rng().gen_range(0..10).to_string())
-            .collect();
+            // Generate 2 random digits
+            let digits: String = (0..2)
+                .map(|_| thread_rng().gen_range(0..10).to_string())
+                .collect();
         
-        // Format the ID as "UCLA_word##"
-        let id = format!("UCLA_{}{}", word, digits);
+            // Format the ID as "UCLA_word##"
+            let id = format!("UCLA_{}{}", word, digits);
         
-        // Check if this ID is already used
-        let id_exists = diesel::dsl::select(diesel::dsl::exists(
-            registered_users::table.filter(registered_users::user_id.eq(&id)),
-        ))
-        .get_result::<bool>(connection)
-        .unwrap_or(false);
+            // Check if this ID is already used
+            let id_exists = diesel::dsl::select(diesel::dsl::exists(
+                registered_users::table.filter(registered_users::user_id.eq(&id)),
+            ))
+            .get_result::<bool>(connection)?;
         
-        if !id_exists {
-            break id;
-        }
-    };
-
-    // Record email hash for tracking (only if email reuse prevention is enabled)
-    #[cfg(not(feature = "allow_email_reuse"))]
-    {
-        let email_hash = hash_email(&payload.email);
-        let domain = email_domain(&payload.email);
-        match insert_into(email_registry::table)
-            .values((email_registry::email_hash.eq(&email_hash.clone()), email_registry::email_domain.eq(domain.as_deref())))
-            .execute(connection) {
-                Ok(_) => println!("-> Email hash has been recorded"),
-                Err(e) => {
-                    eprintln!("Error inserting mail hash: {}", e);
-                    return GenerateUserIdResponse {
-                        success: false,
-                        user_id: String::new(),
-                        message: format!("Database error while recording email: {}", e),
-                    }.into_response();
-                }
+            if !id_exists {
+                break id;
             }
-    }
-
-    #[cfg(feature = "allow_email_reuse")]
-    {
-        println!("-> Email reuse is allowed, skipping email hash recording");
-    }
-
-    // Insert the new user_id (without username) into the database so further checks will succeed
-    let new_user = NewRegisteredUser { user_id: user_id.clone(), username: None };
-    match insert_into(registered_users::table)
-        .values(&new_user)
-        .execute(connection) {
-            Ok(count) => println!("→ Inserted {} row(s) for user_id {}", count, user_id),
-        Err(e) => {
-            eprintln!("Error inserting new user_id into database: {}", e);
-            return GenerateUserIdResponse {
-                success: false,
-                user_id: String::new(),
-                message: format!("Database insertion error: {}", e),
-            }.into_response();
+        };
+
+        // Record email hash for tracking (only if email reuse prevention is enabled)
+        #[cfg(not(feature = "allow_email_reuse"))]
+        {
+            let email_hash = hash_email(&payload.email);
+            let domain = email_domain(&payload.email);
+            insert_into(email_registry::table)
+                .values((email_registry::email_hash.eq(&email_hash.clone()), email_registry::email_domain.eq(domain.as_deref())))
+                .execute(connection)?;
+            tracing::info!("Email hash has been recorded");
         }
-    }
 
-    // Send the email
-    send_user_email(&user_id, &payload.email);
-
-    GenerateUserIdResponse {
-        success: true,
-        user_id,
-        message: "User ID generated successfully. In production, this would be emailed to the provided address.".to_string(),
-    }.into_response()
+        #[cfg(feature = "allow_email_reuse")]
+        {
+            tracing::info!("Email reuse is allowed, skipping email hash recording");
+        }
 
+        // Insert the new user_id (without username) into the database so further checks will succeed
+        let new_user = NewRegisteredUser { user_id: user_id.clone(), username: None };
+        let count = insert_into(registered_users::table)
+            .values(&new_user)
+            .execute(connection)?;
+        record_user_id(&user_id);
+        tracing::info!("Inserted {} row(s) for user_id {}", count, user_id);
+
+        // Send the email
+        send_user_email(&user_id, &payload.email);
+
+        Ok(GenerateUserIdResponse {
+            success: true,
+            user_id,
+            message: "User ID generated successfully. In production, this would be emailed to the provided address.".to_string(),
+        })
+    })
+    .await
 }
\ No newline at end of file
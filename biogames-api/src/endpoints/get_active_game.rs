@@ -0,0 +1,48 @@
+use axum::{extract::{Path, Query, State}, Json};
+use serde::Deserialize;
+
+use crate::{
+    run_db, PgPool,
+    error::ApiError,
+    games::{challenge_progress, unfinished_game},
+    models::{ActiveGameResponse, GameMode},
+    telemetry::{record_game_id, record_user_id}
+};
+
+#[derive(Deserialize)]
+pub struct ActiveGameParams {
+    mode: Option<String>,
+}
+
+/// The user's most recent unfinished game (of `mode`, if given), so the client
+/// can offer to continue it after a refresh.
+pub async fn get_active_game(
+    State(pool): State<PgPool>,
+    Path(user_id): Path<String>,
+    Query(params): Query<ActiveGameParams>
+) -> Result<Json<ActiveGameResponse>, ApiError> {
+    record_user_id(&user_id);
+    let mode = params.mode
+        .as_deref()
+        .map(|mode| mode.parse::<GameMode>().map_err(|e| ApiError::validation("unknown_mode", e.to_string())))
+        .transpose()?;
+
+    run_db(&pool, move |connection| {
+        let game = unfinished_game(connection, &user_id, mode)?.ok_or_else(|| ApiError::not_found(
+            "no_active_game",
+            format!("User {} has no unfinished game", user_id)
+        ))?;
+        record_game_id(game.id);
+        let progress = challenge_progress(connection, game.id)?;
+
+        Ok(Json(ActiveGameResponse {
+            id: game.id,
+            game_type: game.game_type,
+            user: game.username,
+            started_at: game.started_at,
+            completed_challenges: progress.completed,
+            total_challenges: progress.total
+        }))
+    })
+    .await
+}
@@ -1,150 +1,77 @@
 use axum::{
-    body::Body, extract::{Path, Query}, http::{header::{CONTENT_TYPE, CACHE_CONTROL, CONTENT_LENGTH}, StatusCode}, response::{AppendHeaders, IntoResponse}
+    body::Body, extract::{Path, State}, http::header::{CONTENT_TYPE, CACHE_CONTROL, CONTENT_LENGTH}, response::{AppendHeaders, IntoResponse}
 };
 use diesel::prelude::*;
 use tokio_util::io::ReaderStream;
-use once_cell::sync::Lazy;
-use serde::Deserialize;
+use std::sync::Arc;
 
 
 use crate::{
-    establish_db_connection,
+    config::AppConfig,
+    run_db, PgPool,
+    error::ApiError,
+    telemetry::{record_challenge_id, record_game_id},
     models::{Challenge, Her2Core},
     schema::{challenges::{self}, her2_cores}
 };
 
-#[derive(Deserialize)]
-pub struct ChallengeParams {
-    mode: Option<String>,
-}
-
-static TEST_IMAGE_IDS: Lazy<Vec<i32>> = Lazy::new(|| {
-    vec![345,
-    20125,
-    23246,
-    6134,
-    9192,
-    4376,
-    1162,
-    22787,
-    9809,
-    19324,
-    2907,
-    14342,
-    14795,
-    438,
-    12330,
-    10186,
-    8781,
-    12076,
-    19052,
-    6547,
-    5077,
-    8050,
-    9934,
-    23774,
-    10636,
-    13660,
-    20394,
-    18529,
-    19444,
-    4625,
-    19430,
-    23853,
-    210,
-    16056,
-    5231,
-    940,
-    8939,
-    22438,
-    12988,
-    15627,
-    3138,
-    18219,
-    18021,
-    19185,
-    22208,
-    22696,
-    15629,
-    9052,
-    23770,
-    18238]
-});
-
 pub async fn get_challenge_core(
+    State(pool): State<PgPool>,
+    State(config): State<Arc<AppConfig>>,
     Path(challenge_id): Path<i32>,
-    Query(params): Query<ChallengeParams>,
-) -> impl IntoResponse {
+) -> Result<impl IntoResponse, ApiError> {
     
-    let is_test = params.mode.as_deref() == Some("test");
-    let connection = &mut establish_db_connection();
-
+    record_challenge_id(challenge_id);
     tracing::info!("Processing challenge_id: {}", challenge_id);
-    tracing::info!("is_test: {}", is_test);
 
-    let result = challenges::table
-        .inner_join(her2_cores::table)
-        .filter(challenges::id.eq(challenge_id))
-        .select((Challenge::as_select(), Her2Core::as_select()))
-        .first::<(Challenge, Her2Core)>(connection);
+    let (challenge, core) = run_db(&pool, move |connection| {
+        challenges::table
+            .inner_join(her2_cores::table)
+            .filter(challenges::id.eq(challenge_id))
+            .select((Challenge::as_select(), Her2Core::as_select()))
+            .first::<(Challenge, Her2Core)>(connection)
+            .optional()?
+            .ok_or_else(|| ApiError::not_found("challenge_not_found", format!("Challenge {} not found", challenge_id)))
+    }).await?;
+    record_game_id(challenge.game_id);
+    tracing::debug!("Found challenge: {:?}, core: {:?}", challenge, core);
 
-    match result {
-        Ok((ref challenge, ref core)) => {
-            tracing::debug!("Found challenge: {:?}, core: {:?}", challenge, core);
-            // Proceed with your logic
-        }
-        Err(diesel::result::Error::NotFound) => {
-            tracing::debug!("No results found for challenge_id: {}", challenge_id);
-            return StatusCode::NOT_FOUND.into_response();
-        }
-        Err(e) => {
-            tracing::error!("Database error: {:?}", e);
-            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
-        }
-    }
-
-    let (_challenge, core) = result.unwrap();
     tracing::debug!("core file name: {}", core.file_name);
-    let file_path = core.file_name.clone();
-    let file = match tokio::fs::File::open(&file_path).await {
-        Ok(f) => f,
-        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response()
-    };
-    let metadata = match file.metadata().await {
-        Ok(m) => m,
-        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response()
-    };
-    let file_size = metadata.len();
+    let file_path = config.resolve_image_path(&core.file_name).await;
+    let file = tokio::fs::File::open(&file_path).await.map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => ApiError::not_found(
+            "image_not_found",
+            format!("Image for challenge {} not found", challenge_id)
+        ),
+        _ => ApiError::Io(e)
+    })?;
+    let file_size = file.metadata().await?.len();
+    metrics::counter!("biogames_image_bytes_served_total", "endpoint" => "challenge_core").increment(file_size);
 
     // Update started_at if it's null
-    match diesel::update(challenges::table)
-        .filter(challenges::id.eq(challenge_id))
-        .filter(challenges::started_at.is_null())
-        .set(challenges::started_at.eq(chrono::offset::Utc::now()))
-        .execute(connection)
-    {
-        Ok(0) => {
-            // This means started_at was already set, which is fine.
-            // Or, challenge_id didn't exist, but we would have caught that earlier.
-            tracing::debug!("Challenge {} started_at was already set or challenge not found for update.", challenge_id);
-        }
-        Ok(_) => {
-            tracing::info!("Successfully set started_at for challenge {}", challenge_id);
-        }
-        Err(e) => {
-            tracing::error!("Failed to set started_at for challenge {}: {:?}", challenge_id, e);
-            return StatusCode::INTERNAL_SERVER_ERROR.into_response(); // Return an error response
-        }
+    let updated = run_db(&pool, move |connection| {
+        Ok(diesel::update(challenges::table)
+            .filter(challenges::id.eq(challenge_id))
+            .filter(challenges::started_at.is_null())
+            .set(challenges::started_at.eq(chrono::offset::Utc::now()))
+            .execute(connection)?)
+    }).await?;
+
+    if updated == 0 {
+        // This means started_at was already set, which is fine.
+        // Or, challenge_id didn't exist, but we would have caught that earlier.
+        tracing::debug!("Challenge {} started_at was already set or challenge not found for update.", challenge_id);
+    } else {
+        tracing::info!("Successfully set started_at for challenge {}", challenge_id);
     }
 
     let stream = ReaderStream::new(file);
     let body = Body::from_stream(stream);
 
     // Tell browsers to cache these cores for 24h, serve correct mime, and set Content-Length
-    let length_value = file_size.to_string();
-    (AppendHeaders([
-        (CONTENT_TYPE, "image/png"),
-        (CONTENT_LENGTH, &length_value),
-        (CACHE_CONTROL, "public, max-age=86400, immutable")
-    ]), body).into_response()
+    Ok((AppendHeaders([
+        (CONTENT_TYPE, "image/png".to_string()),
+        (CONTENT_LENGTH, file_size.to_string()),
+        (CACHE_CONTROL, "public, max-age=86400, immutable".to_string())
+    ]), body))
 }
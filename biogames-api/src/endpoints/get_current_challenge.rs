@@ -1,11 +1,14 @@
-use axum::{extract::{Path, Query}, http::StatusCode, response::IntoResponse};
+use axum::extract::{Path, Query, State};
+use chrono::{Duration, Utc};
 use diesel::prelude::*;
 use serde::Deserialize;
 
 use crate::{
-    establish_db_connection,
+    run_db, PgPool,
+    error::ApiError,
+    telemetry::record_game_id,
     models::{Challenge, CurrentChallengeResponse, Her2Core},
-    schema::{challenges, her2_cores}
+    schema::{challenges, games, her2_cores}
 };
 
 // Define a struct for the query parameters
@@ -15,47 +18,65 @@ pub struct GetCurrentChallengeParams {
 }
 
 pub async fn get_current_challenge(
+    State(pool): State<PgPool>,
     Path(game_id): Path<i32>,
     Query(params): Query<GetCurrentChallengeParams>, // <-- Use the new params struct
-) -> impl IntoResponse {
-    let connection = &mut establish_db_connection();
-
-    let results = challenges::table
-        .inner_join(her2_cores::table)
-        .filter(challenges::game_id.eq(game_id))
-        .select((Challenge::as_select(), Her2Core::as_select()))
-        .order_by(challenges::id) // Ensure consistent order
-        .get_results::<(Challenge, Her2Core)>(connection);
-
-    if let Err(e) = results {
-        eprintln!("Error fetching challenges: {:?}", e); // Log the error
-        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
-    }
-
-    let all_challenges_for_game = results.unwrap();
-    let total_challenges = all_challenges_for_game.len() as i32;
-
-    // Calculate the actual number of challenges with a guess in the DB
-    let actual_completed_in_db = all_challenges_for_game.iter()
-        .filter(|(ch, _)| ch.guess.is_some())
-        .count() as i32;
-
-    // Determine which uncompleted challenge to target based on completed_count param
-    // Defaults to 0, meaning the first uncompleted challenge.
-    let target_uncompleted_index = params.completed_count.unwrap_or(0);
-
-    // Get the ID and Core ID of the target uncompleted challenge
-    let target_challenge_details = all_challenges_for_game.iter()
-        .filter(|(ch, _)| ch.guess.is_none()) // Only consider un-guessed challenges
-        .nth(target_uncompleted_index as usize); // Get the Nth one (0-indexed)
-
-    let target_challenge_id = target_challenge_details.map(|(ch, _)| ch.id);
-    let target_core_id = target_challenge_details.map(|(_, core)| core.id); // Extract core_id from Her2Core
-
-    CurrentChallengeResponse {
-        id: target_challenge_id,
-        core_id: target_core_id, // Populate the new field
-        completed_challenges: actual_completed_in_db, // Always return actual completed count from DB
-        total_challenges,
-    }.into_response()
+) -> Result<CurrentChallengeResponse, ApiError> {
+    record_game_id(game_id);
+    run_db(&pool, move |connection| {
+        let time_limit_secs = games::table
+            .find(game_id)
+            .select(games::time_limit_secs)
+            .first::<Option<i32>>(connection)
+            .optional()?
+            .ok_or_else(|| ApiError::not_found("game_not_found", format!("Game {} not found", game_id)))?;
+
+        let all_challenges_for_game = challenges::table
+            .inner_join(her2_cores::table)
+            .filter(challenges::game_id.eq(game_id))
+            .select((Challenge::as_select(), Her2Core::as_select()))
+            .order_by(challenges::id) // Ensure consistent order
+            .get_results::<(Challenge, Her2Core)>(connection)?;
+
+        let total_challenges = all_challenges_for_game.len() as i32;
+
+        // Calculate the actual number of challenges with a guess in the DB
+        let actual_completed_in_db = all_challenges_for_game.iter()
+            .filter(|(ch, _)| ch.guess.is_some())
+            .count() as i32;
+
+        // Determine which uncompleted challenge to target based on completed_count param
+        // Defaults to 0, meaning the first uncompleted challenge.
+        let target_uncompleted_index = params.completed_count.unwrap_or(0);
+
+        // Get the ID and Core ID of the target uncompleted challenge
+        let target_challenge_details = all_challenges_for_game.iter()
+            .filter(|(ch, _)| ch.guess.is_none()) // Only consider un-guessed challenges
+            .nth(target_uncompleted_index as usize); // Get the Nth one (0-indexed)
+
+        let target_challenge_id = target_challenge_details.map(|(ch, _)| ch.id);
+        let target_core_id = target_challenge_details.map(|(_, core)| core.id); // Extract core_id from Her2Core
+
+        // Timed games: the clock starts when the core is served, the same
+        // started_at submit_challenge checks the deadline against.
+        let time_limit = time_limit_secs.map(|secs| Duration::seconds(i64::from(secs)));
+        let deadline = time_limit.zip(target_challenge_details.and_then(|(ch, _)| ch.started_at))
+            .map(|(limit, started_at)| started_at + limit);
+        let remaining = match (time_limit, deadline) {
+            (_, Some(deadline)) => Some((deadline - Utc::now()).max(Duration::zero())),
+            (limit, None) if target_challenge_details.is_some() => limit,
+            _ => None,
+        };
+
+        Ok(CurrentChallengeResponse {
+            id: target_challenge_id,
+            core_id: target_core_id, // Populate the new field
+            completed_challenges: actual_completed_in_db, // Always return actual completed count from DB
+            total_challenges,
+            time_limit_ms: time_limit.map(|limit| limit.num_milliseconds()),
+            deadline,
+            remaining_ms: remaining.map(|remaining| remaining.num_milliseconds()),
+        })
+    })
+    .await
 }
@@ -1,110 +1,138 @@
-use axum::{extract::Path, http::StatusCode, response::IntoResponse};
+use std::sync::Arc;
+
+use axum::extract::{Path, State};
 use diesel::prelude::*;
 
 use crate::{
-    establish_db_connection,
+    run_db, PgPool,
+    config::AppConfig,
+    error::ApiError,
+    telemetry::record_game_id,
     models::{Challenge, Game, GameResponse, GameResultResponse, GameResultsResponse, Her2Core},
     schema::{challenges, games, her2_cores},
-    scoring::get_score,
+    scoring::{get_score, get_timed_out_score},
 };
 
-pub async fn get_game(Path(game_id): Path<i32>) -> impl IntoResponse {
-    let connection = &mut establish_db_connection();
-
-    tracing::info!("Processing game_id: {}", game_id);
-
-    let results = games::table
-        .inner_join(challenges::table)
-        .inner_join(her2_cores::table.on(her2_cores::id.eq(challenges::core_id)))
-        .filter(games::id.eq(game_id))
-        .order_by(challenges::id)
-        .get_results::<(Game, Challenge, Her2Core)>(connection);
-
-    if let Err(e) = results {
-        tracing::error!("Database error: {:?}", e);
-        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
-    }
-
-    let results = results.unwrap();
-
-    if results.len() == 0 {
-        tracing::warn!("No results found for game_id: {}", game_id);
-        return StatusCode::BAD_REQUEST.into_response();
-    }
-
-    let (game, _, _) = &results[0];
+pub async fn get_game(
+    State(pool): State<PgPool>,
+    State(config): State<Arc<AppConfig>>,
+    Path(game_id): Path<i32>
+) -> Result<GameResponse, ApiError> {
+    record_game_id(game_id);
+    run_db(&pool, move |connection| {
+        tracing::info!("Processing game_id: {}", game_id);
+
+        let results = games::table
+            .inner_join(challenges::table)
+            .inner_join(her2_cores::table.on(her2_cores::id.eq(challenges::core_id)))
+            .filter(games::id.eq(game_id))
+            .order_by(challenges::id)
+            .get_results::<(Game, Challenge, Her2Core)>(connection)?;
+
+        if results.is_empty() {
+            tracing::warn!("No results found for game_id: {}", game_id);
+            // A quit game keeps its row but loses its unattempted challenges.
+            let game_exists = diesel::select(diesel::dsl::exists(games::table.find(game_id)))
+                .get_result::<bool>(connection)?;
+            return Err(if game_exists {
+                ApiError::validation("no_completed_challenges", format!("Game {} has no completed challenges", game_id))
+            } else {
+                ApiError::not_found("game_not_found", format!("Game {} not found", game_id))
+            });
+        }
 
-    // Get completed challenges, whether or not the game score is finalized
-    let completed_challenges = results.iter()
-        .filter(|(_, ch, _)| ch.guess.is_some())
-        .count();
+        let (game, _, _) = &results[0];
 
-    // If the game has no completed challenges, return error
-    if completed_challenges == 0 {
-        tracing::warn!("Game {} has no completed challenges", game_id);
-        return StatusCode::BAD_REQUEST.into_response();
-    }
+        // Get completed challenges, whether or not the game score is finalized
+        let completed_challenges = results.iter()
+            .filter(|(_, ch, _)| ch.guess.is_some())
+            .count();
 
-    // Updated to use the confusion matrix
-    fn points(ch: &Challenge, co: &Her2Core) -> i32 {
-        if let Some(guess) = ch.guess {
-            get_score(guess, co.score)
-        } else {
-            0 // Default for challenges without guesses
+        // If the game has no completed challenges, return error
+        if completed_challenges == 0 {
+            tracing::warn!("Game {} has no completed challenges", game_id);
+            return Err(ApiError::validation(
+                "no_completed_challenges",
+                format!("Game {} has no completed challenges", game_id)
+            ));
         }
-    }
-
-    let game_results = results.iter()
-        .filter(|(_, ch, _)| ch.guess.is_some())
-        .map(|(_, ch, co)| GameResultResponse {
-            challenge_id: ch.id,
-            guess: ch.guess.unwrap(),
-            correct_score: co.score,
-            seconds: (ch.submitted_at.unwrap_or_else(chrono::Utc::now) - 
-                     ch.started_at.unwrap_or_else(chrono::Utc::now))
-                .num_milliseconds() as f64 / 1000_f64,
-            points: points(ch, co)
-        })
-        .collect::<Vec<_>>();
-
-    let total_points = game_results.iter()
-        .map(|r| r.points)
-        .sum();
 
-    // Update the categorization code to use actual owned values rather than references
-    let severe_mistakes = game_results.iter()
-        .filter(|r| r.points <= -3)
-        .cloned()
-        .collect::<Vec<_>>();
+        // The points submit_challenge stored, so the page agrees with
+        // games.score and the leaderboard. Rows scored before points were
+        // stored fall back to the confusion matrix.
+        let timeout_penalty = config.scoring.timeout_penalty;
+        let points = |ch: &Challenge, co: &Her2Core| -> i32 {
+            match (ch.points, ch.guess) {
+                (Some(points), _) => points,
+                (None, _) if ch.timed_out => get_timed_out_score(timeout_penalty),
+                (None, Some(guess)) => get_score(guess, co.score),
+                (None, None) => 0,
+            }
+        };
+
+        let game_results = results.iter()
+            .filter(|(_, ch, _)| ch.guess.is_some())
+            .map(|(_, ch, co)| GameResultResponse {
+                challenge_id: ch.id,
+                guess: ch.guess.unwrap(),
+                correct_score: co.score,
+                seconds: (ch.submitted_at.unwrap_or_else(chrono::Utc::now) - 
+                         ch.started_at.unwrap_or_else(chrono::Utc::now))
+                    .num_milliseconds() as f64 / 1000_f64,
+                points: points(ch, co),
+                timed_out: ch.timed_out
+            })
+            .collect::<Vec<_>>();
+
+        let total_points = game_results.iter()
+            .map(|r| r.points)
+            .sum();
+
+        // Update the categorization code to use actual owned values rather than references
+        let severe_mistakes = game_results.iter()
+            .filter(|r| !r.timed_out && r.points <= -3)
+            .cloned()
+            .collect::<Vec<_>>();
         
-    let moderate_mistakes = game_results.iter()
-        .filter(|r| r.points == -2)
-        .cloned()
-        .collect::<Vec<_>>();
+        let moderate_mistakes = game_results.iter()
+            .filter(|r| !r.timed_out && r.points == -2)
+            .cloned()
+            .collect::<Vec<_>>();
         
-    let mild_mistakes = game_results.iter()
-        .filter(|r| r.points == -1)
-        .cloned()
-        .collect::<Vec<_>>();
+        let mild_mistakes = game_results.iter()
+            .filter(|r| !r.timed_out && r.points == -1)
+            .cloned()
+            .collect::<Vec<_>>();
         
-    let correct = game_results.iter()
-        .filter(|r| r.points == 5)
-        .cloned()
-        .collect::<Vec<_>>();
-
-    // Update the GameResultsResponse with our categorized results
-    let grouped_results = GameResultsResponse {
-        severe_mistakes,
-        moderate_mistakes,
-        mild_mistakes,
-        correct,
-    };
-
-    // Return the game response regardless of whether game.score is set
-    return GameResponse {
-        id: game_id,
-        user: game.username.clone(),
-        results: Some(grouped_results),
-        total_points: Some(total_points)
-    }.into_response();
+        let correct = game_results.iter()
+            .filter(|r| !r.timed_out && r.points == 5)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let timed_out = game_results.iter()
+            .filter(|r| r.timed_out)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        // Update the GameResultsResponse with our categorized results
+        let grouped_results = GameResultsResponse {
+            severe_mistakes,
+            moderate_mistakes,
+            mild_mistakes,
+            correct,
+            timed_out,
+        };
+
+        // Return the game response regardless of whether game.score is set
+        Ok(GameResponse {
+            id: game_id,
+            user: game.username.clone(),
+            results: Some(grouped_results),
+            total_points: Some(total_points),
+            completed_challenges: None,
+            total_challenges: None,
+            resumed: None
+        })
+    })
+    .await
 }
@@ -1,10 +1,11 @@
-use axum::{extract::Query, http::StatusCode, response::IntoResponse};
+use axum::extract::{Query, State};
 use diesel::{sql_query, RunQueryDsl};
 use diesel::sql_types::{Text, Integer};
 use diesel::QueryableByName;
 
 use crate::{
-    establish_db_connection,
+    run_db, PgPool,
+    error::ApiError,
     models::{
         GetLeaderboardRequest, LeaderboardEntryResponse, LeaderboardResponse
     }
@@ -20,54 +21,50 @@ struct LeaderboardEntry {
     avg_time_taken_ms: i32,
 }
 
-pub async fn get_leaderboard(Query(_body): Query<GetLeaderboardRequest>) -> impl IntoResponse {
-    let connection = &mut establish_db_connection();
-
-    let query = r#"
-            SELECT
-                username,
-                AVG(score)::integer AS avg_score,
-                AVG(time_taken_ms)::integer AS avg_time_taken_ms
-            FROM (
+pub async fn get_leaderboard(State(pool): State<PgPool>, Query(_body): Query<GetLeaderboardRequest>) -> Result<LeaderboardResponse, ApiError> {
+    run_db(&pool, move |connection| {
+        let query = r#"
                 SELECT
                     username,
-                    score,
-                    time_taken_ms,
-                    user_id,
-                    ROW_NUMBER() OVER (
-                        PARTITION BY user_id
-                        ORDER BY score DESC, time_taken_ms ASC
-                    ) AS rank
-                FROM games
-                WHERE score IS NOT NULL 
-                  AND time_taken_ms IS NOT NULL 
-                  AND game_type = 'training'
-                  AND username IS NOT NULL
-            ) ranked_games
-            WHERE rank <= 2
-            GROUP BY user_id, username
-            ORDER BY avg_score DESC, avg_time_taken_ms ASC;
-        "#;
+                    AVG(score)::integer AS avg_score,
+                    AVG(time_taken_ms)::integer AS avg_time_taken_ms
+                FROM (
+                    SELECT
+                        username,
+                        score,
+                        time_taken_ms,
+                        user_id,
+                        ROW_NUMBER() OVER (
+                            PARTITION BY user_id
+                            ORDER BY score DESC, time_taken_ms ASC
+                        ) AS rank
+                    FROM games
+                    WHERE score IS NOT NULL 
+                      AND time_taken_ms IS NOT NULL 
+                      -- Only training games compete; review games replay the
+                      -- player's own mistakes and the tests are held out.
+                      AND game_type = 'training'
+                      AND username IS NOT NULL
+                ) ranked_games
+                WHERE rank <= 2
+                GROUP BY user_id, username
+                ORDER BY avg_score DESC, avg_time_taken_ms ASC;
+            "#;
 
-    let results = sql_query(query)
-        .get_results::<LeaderboardEntry>(connection);
+        let entries = sql_query(query)
+            .get_results::<LeaderboardEntry>(connection)?;
 
-    match results {
-        Err(error) => {
-            tracing::debug!("error at get_leaderboard: {}", error);
-            StatusCode::INTERNAL_SERVER_ERROR.into_response()
-        },
-        Ok(entries) => LeaderboardResponse {
+        Ok(LeaderboardResponse {
             entries: entries.into_iter()
-            
-            .map(|entry| LeaderboardEntryResponse {
-                username: entry.username,
-                score: entry.avg_score,
-                time_taken_ms: entry.avg_time_taken_ms as i32,
-                // TODO fix timestamp
-                timestamp: chrono::offset::Utc::now()
-            })
-            .collect()
-        }.into_response()
-    }
+                .map(|entry| LeaderboardEntryResponse {
+                    username: entry.username,
+                    score: entry.avg_score,
+                    time_taken_ms: entry.avg_time_taken_ms,
+                    // TODO fix timestamp
+                    timestamp: chrono::offset::Utc::now()
+                })
+                .collect()
+        })
+    })
+    .await
 }
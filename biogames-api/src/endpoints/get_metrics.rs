@@ -0,0 +1,28 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::{header, HeaderMap},
+    response::IntoResponse
+};
+use metrics_exporter_prometheus::PrometheusHandle;
+
+use crate::{auth::check_bearer, config::AppConfig, error::ApiError};
+
+/// Prometheus text exposition of every `biogames_*` metric. Open unless
+/// `METRICS_TOKEN` is configured, in which case scrapers must send it as a
+/// bearer token.
+pub async fn get_metrics(
+    State(handle): State<PrometheusHandle>,
+    State(config): State<Arc<AppConfig>>,
+    headers: HeaderMap
+) -> Result<impl IntoResponse, ApiError> {
+    if let Some(token) = config.metrics.token.as_deref() {
+        check_bearer(&headers, token)?;
+    }
+
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        handle.render()
+    ))
+}
@@ -1,21 +1,23 @@
 use axum::{
-    extract::Query,
-    http::StatusCode,
-    response::{IntoResponse, Json},
+    extract::{Query, State},
+    response::Json,
 };
 use diesel::prelude::*;
 use diesel::sql_types::Integer; // For RANDOM() if id is integer
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    establish_db_connection,
-    schema::her2_cores, // Assuming schema is here
+    run_db, PgPool,
+    error::ApiError,
+    models::GameMode,
+    protocol::{DrawMethod, Protocol},
+    selection::fixed_first_core,
+    schema::{core_set_members, core_sets, her2_cores}, // Assuming schema is here
 };
 
 #[derive(Deserialize)]
 pub struct PreviewParams {
-    #[serde(alias = "mode")]
-    _mode: Option<String>, // Mode is not used yet, but captured for future use
+    mode: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -24,26 +26,51 @@ pub struct PreviewCoreIdResponse {
 }
 
 pub async fn get_preview_core_id(
-    Query(_params): Query<PreviewParams>,
-) -> impl IntoResponse {
-    let connection = &mut establish_db_connection();
+    State(pool): State<PgPool>,
+    Query(params): Query<PreviewParams>,
+) -> Result<Json<PreviewCoreIdResponse>, ApiError> {
+    let mode = params.mode
+        .as_deref()
+        .map(|mode| mode.parse::<GameMode>().map_err(|e| ApiError::validation("unknown_mode", e.to_string())))
+        .transpose()?;
 
-    match her2_cores::table
-        .select(her2_cores::id)
-        .order(diesel::dsl::sql::<Integer>("RANDOM()")) // PostgreSQL specific for random row
-        .first::<i32>(connection)
-    {
-        Ok(core_id) => Json(PreviewCoreIdResponse {
-            her2_core_id: core_id,
-        })
-        .into_response(),
-        Err(diesel::NotFound) => {
-            eprintln!("No Her2Cores found in the database.");
-            (StatusCode::NOT_FOUND, "No Her2Cores available for preview").into_response()
+    run_db(&pool, move |connection| {
+        // The preview becomes the game's first challenge, so for a mode it
+        // comes from the core set that mode's phase draws from. A fixed-order
+        // phase always starts with the same core, so that one is previewed.
+        let phase = match mode {
+            Some(mode) => Protocol::load_active(connection)?.phase(mode).cloned(),
+            None => None,
+        };
+        if let Some(phase) = phase.as_ref().filter(|phase| phase.draw == DrawMethod::Random && !phase.uses_test_form) {
+            if let Some(fixed_seed) = phase.fixed_seed {
+                let set = crate::core_sets::find(connection, &phase.core_set)?;
+                let first = fixed_first_core(connection, set.id, phase.sampling, phase.challenges_per_game, fixed_seed)?;
+                let her2_core_id = first.ok_or_else(|| ApiError::not_found("core_not_found", "No Her2Cores available for preview"))?;
+                return Ok(Json(PreviewCoreIdResponse { her2_core_id }));
+            }
         }
-        Err(e) => {
-            eprintln!("Error fetching random Her2Core ID: {:?}", e);
-            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        let core_set = phase.map(|phase| phase.core_set);
+
+        let core_id = match core_set {
+            Some(core_set) => core_set_members::table
+                .inner_join(core_sets::table)
+                .filter(core_sets::name.eq(core_set))
+                .select(core_set_members::core_id)
+                .order(diesel::dsl::sql::<Integer>("RANDOM()"))
+                .first::<i32>(connection)
+                .optional()?,
+            None => her2_cores::table
+                .select(her2_cores::id)
+                .order(diesel::dsl::sql::<Integer>("RANDOM()")) // PostgreSQL specific for random row
+                .first::<i32>(connection)
+                .optional()?,
         }
-    }
+        .ok_or_else(|| ApiError::not_found("core_not_found", "No Her2Cores available for preview"))?;
+
+        Ok(Json(PreviewCoreIdResponse {
+            her2_core_id: core_id,
+        }))
+    })
+    .await
 } 
\ No newline at end of file
@@ -0,0 +1,18 @@
+use axum::{extract::{Path, State}, Json};
+
+use crate::{
+    run_db, PgPool,
+    error::ApiError,
+    progression::Progress,
+    telemetry::record_user_id
+};
+
+/// Where the participant stands in the study and what they may do next, with
+/// the reasons anything is unavailable, so the client never re-derives the rules.
+pub async fn get_progress(
+    State(pool): State<PgPool>,
+    Path(user_id): Path<String>
+) -> Result<Json<Progress>, ApiError> {
+    record_user_id(&user_id);
+    run_db(&pool, move |connection| Ok(Json(Progress::load(connection, &user_id)?))).await
+}
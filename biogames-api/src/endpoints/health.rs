@@ -0,0 +1,113 @@
+use std::sync::Arc;
+
+use axum::{extract::State, http::StatusCode, Json};
+use diesel::{dsl::sql, sql_types::Integer, QueryDsl, RunQueryDsl};
+use diesel_migrations::MigrationHarness;
+use serde::Serialize;
+
+use crate::{
+    config::AppConfig,
+    error::ApiError,
+    run_db, PgPool, MIGRATIONS,
+    protocol::Protocol,
+    schema::her2_cores
+};
+
+#[derive(Serialize)]
+pub struct HealthResponse {
+    pub status: &'static str
+}
+
+#[derive(Serialize)]
+pub struct ReadinessResponse {
+    pub status: &'static str,
+    pub database: CheckResult,
+    pub migrations: CheckResult,
+    pub images: CheckResult,
+    pub her2_cores: CheckResult,
+    pub study_protocol: CheckResult
+}
+
+#[derive(Serialize)]
+pub struct CheckResult {
+    pub ok: bool,
+    pub detail: String
+}
+
+impl CheckResult {
+    fn pass(detail: impl Into<String>) -> Self {
+        CheckResult { ok: true, detail: detail.into() }
+    }
+
+    fn fail(detail: impl Into<String>) -> Self {
+        CheckResult { ok: false, detail: detail.into() }
+    }
+}
+
+/// Liveness: the process is up and serving requests. Touches nothing else, so
+/// a database outage never gets the process restarted.
+pub async fn healthz() -> Json<HealthResponse> {
+    Json(HealthResponse { status: "ok" })
+}
+
+/// Readiness: every dependency a game needs is available. Returns 503 with the
+/// same body when any check fails so the failing piece is visible at a glance.
+pub async fn readyz(
+    State(pool): State<PgPool>,
+    State(config): State<Arc<AppConfig>>
+) -> (StatusCode, Json<ReadinessResponse>) {
+    let db_checks = run_db(&pool, |connection| {
+        sql::<Integer>("SELECT 1").get_result::<i32>(connection)?;
+        let pending = connection.pending_migrations(MIGRATIONS)
+            .map_err(|e| ApiError::internal(e.to_string()))?
+            .len();
+        let core_count = her2_cores::table.count().get_result::<i64>(connection)?;
+        let protocol = Protocol::load_active(connection).map_err(|e| e.to_string());
+        Ok((pending, core_count, protocol))
+    }).await;
+
+    let (database, migrations, her2_cores, study_protocol) = match db_checks {
+        Ok((pending, core_count, protocol)) => (
+            CheckResult::pass("reachable"),
+            if pending == 0 {
+                CheckResult::pass("up to date")
+            } else {
+                CheckResult::fail(format!("{} pending", pending))
+            },
+            if core_count > 0 {
+                CheckResult::pass(format!("{} rows", core_count))
+            } else {
+                CheckResult::fail("table is empty")
+            },
+            match protocol {
+                Ok(protocol) if !protocol.phases.is_empty() => {
+                    CheckResult::pass(format!("{} ({} phases)", protocol.name, protocol.phases.len()))
+                }
+                Ok(protocol) => CheckResult::fail(format!("{} has no phases", protocol.name)),
+                Err(e) => CheckResult::fail(e)
+            }
+        ),
+        Err(e) => {
+            // Only the code goes in the body; the details (host names, SQL) stay in the log.
+            tracing::warn!("Readiness database check failed: {}", e);
+            let skipped = || CheckResult::fail("database unreachable");
+            (CheckResult::fail(e.code()), skipped(), skipped(), skipped())
+        }
+    };
+
+    let images = match tokio::fs::read_dir(&config.images.base_path).await {
+        Ok(_) => CheckResult::pass(config.images.base_path.display().to_string()),
+        Err(e) => CheckResult::fail(format!("{}: {}", config.images.base_path.display(), e))
+    };
+
+    let ready = database.ok && migrations.ok && images.ok && her2_cores.ok && study_protocol.ok;
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(ReadinessResponse {
+        status: if ready { "ready" } else { "not_ready" },
+        database,
+        migrations,
+        images,
+        her2_cores,
+        study_protocol
+    }))
+}
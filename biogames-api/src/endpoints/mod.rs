@@ -13,6 +13,12 @@ pub mod register_with_username;
 pub mod get_preview_core_id;
 pub mod get_her2_core_image;
 pub mod analytics;
+pub mod health;
+pub mod get_metrics;
+pub mod get_active_game;
+pub mod get_progress;
+pub mod admin_core_sets;
+pub mod admin_games;
 
 pub use create_game::*;
 pub use get_current_challenge::*;
@@ -29,3 +35,9 @@ pub use register_with_username::register_with_username;
 pub use get_preview_core_id::*;
 pub use get_her2_core_image::*;
 pub use analytics::*;
+pub use health::*;
+pub use get_metrics::*;
+pub use get_active_game::*;
+pub use get_progress::*;
+pub use admin_core_sets::*;
+pub use admin_games::*;
@@ -1,12 +1,13 @@
 use axum::{
+    extract::State,
     response::IntoResponse,
     Json,
 };
-use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl, SelectableHelper};
+use diesel::{ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl, SelectableHelper};
 use serde::{Deserialize, Serialize};
 use validator::Validate;
 
-use crate::establish_db_connection;
+use crate::{run_db, PgPool, error::ApiError, rate_limit::RateLimiter, telemetry::record_user_id};
 use crate::models::{RegisteredUser, NewRegisteredUser, ValidatedRequest};
 use crate::schema::registered_users;
 
@@ -35,92 +36,61 @@ impl IntoResponse for RegisterWithUsernameResponse {
 }
 
 pub async fn register_with_username(
+    State(pool): State<PgPool>,
+    State(limiter): State<RateLimiter>,
     ValidatedRequest(payload): ValidatedRequest<RegisterWithUsernameRequest>,
-) -> impl IntoResponse {
-    let connection = &mut establish_db_connection();
-    
-    // Check if the user_id already exists
-    let user_exists = diesel::dsl::select(diesel::dsl::exists(
-        registered_users::table.filter(registered_users::user_id.eq(&payload.user_id)),
-    ))
-    .get_result::<bool>(connection)
-    .unwrap_or(false);
-    
-    if user_exists {
-        // Check if the user already has a username
-        let user = registered_users::table
+) -> Result<RegisterWithUsernameResponse, ApiError> {
+    record_user_id(&payload.user_id);
+    limiter.check_user(&payload.user_id)?;
+    run_db(&pool, move |connection| {
+        // Check if the user_id already exists
+        let existing_user = registered_users::table
             .filter(registered_users::user_id.eq(&payload.user_id))
             .select(RegisteredUser::as_select())
-            .first::<RegisteredUser>(connection);
-            
-        match user {
-            Ok(user) => {
-                if user.username.is_some() {
-                    return RegisterWithUsernameResponse {
-                        success: false,
-                        user_id: payload.user_id,
-                        username: user.username,
-                        message: "User already has a username registered".to_string(),
-                    }.into_response();
-                }
-                
-                // Update existing user with the username
-                match diesel::update(registered_users::table.find(user.id))
-                    .set(registered_users::username.eq(Some(payload.username.clone())))
-                    .returning(RegisteredUser::as_returning())
-                    .get_result(connection) {
-                        Ok(updated_user) => {
-                            RegisterWithUsernameResponse {
-                                success: true,
-                                user_id: updated_user.user_id,
-                                username: updated_user.username,
-                                message: "Username registered successfully".to_string(),
-                            }.into_response()
-                        },
-                        Err(e) => {
-                            eprintln!("Error updating user with username: {}", e);
-                            (
-                                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                                "Failed to register username".to_string(),
-                            ).into_response()
-                        }
-                    }
-            },
-            Err(e) => {
-                eprintln!("Error retrieving user: {}", e);
-                (
-                    axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                    "Failed to retrieve user information".to_string(),
-                ).into_response()
+            .first::<RegisteredUser>(connection)
+            .optional()?;
+
+        if let Some(user) = existing_user {
+            if user.username.is_some() {
+                return Ok(RegisterWithUsernameResponse {
+                    success: false,
+                    user_id: payload.user_id,
+                    username: user.username,
+                    message: "User already has a username registered".to_string(),
+                });
             }
+
+            // Update existing user with the username
+            let updated_user = diesel::update(registered_users::table.find(user.id))
+                .set(registered_users::username.eq(Some(payload.username.clone())))
+                .returning(RegisteredUser::as_returning())
+                .get_result(connection)?;
+
+            Ok(RegisterWithUsernameResponse {
+                success: true,
+                user_id: updated_user.user_id,
+                username: updated_user.username,
+                message: "Username registered successfully".to_string(),
+            })
+        } else {
+            // Create new user with the user_id and username
+            let new_user = NewRegisteredUser {
+                user_id: payload.user_id.clone(),
+                username: Some(payload.username.clone()),
+            };
+
+            let registered_user = diesel::insert_into(registered_users::table)
+                .values(&new_user)
+                .returning(RegisteredUser::as_returning())
+                .get_result(connection)?;
+
+            Ok(RegisterWithUsernameResponse {
+                success: true,
+                user_id: registered_user.user_id,
+                username: registered_user.username,
+                message: "User registered successfully with username".to_string(),
+            })
         }
-    } else {
-        // Create new user with the user_id and username
-        let new_user = NewRegisteredUser {
-            user_id: payload.user_id.clone(),
-            username: Some(payload.username.clone()),
-        };
-        
-        // Insert into database
-        match diesel::insert_into(registered_users::table)
-            .values(&new_user)
-            .returning(RegisteredUser::as_returning())
-            .get_result(connection) {
-                Ok(registered_user) => {
-                    RegisterWithUsernameResponse {
-                        success: true,
-                        user_id: registered_user.user_id,
-                        username: registered_user.username,
-                        message: "User registered successfully with username".to_string(),
-                    }.into_response()
-                },
-                Err(e) => {
-                    eprintln!("Error inserting user into database: {}", e);
-                    (
-                        axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                        "Failed to register user with username".to_string(),
-                    ).into_response()
-                }
-            }
-    }
-} 
\ No newline at end of file
+    })
+    .await
+}
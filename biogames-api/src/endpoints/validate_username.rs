@@ -1,8 +1,6 @@
 use axum::{
-    extract::{Path, Query},
+    extract::{Path, Query, State},
     Json,
-    response::IntoResponse,
-    http::StatusCode,
 };
 use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
 #[cfg(feature = "training_direct_entry")]
@@ -15,9 +13,11 @@ use serde::Deserialize;
 #[cfg(feature = "training_direct_entry")]
 use chrono::Utc;
 
-use crate::{establish_db_connection, schema::{registered_users}};
+use crate::{run_db, PgPool, error::ApiError, schema::{registered_users}, telemetry::record_user_id};
 #[cfg(feature = "training_direct_entry")]
 use crate::schema::games;
+#[cfg(feature = "training_direct_entry")]
+use crate::{models::{GameMode, UnknownGameMode}, progression::Progress};
 
 #[derive(Deserialize)]
 pub struct ValidateUsernameQuery {
@@ -25,98 +25,95 @@ pub struct ValidateUsernameQuery {
 }
 
 pub async fn validate_username(
+    State(pool): State<PgPool>,
     Path(user_id_str): Path<String>,
     Query(query_params): Query<ValidateUsernameQuery>
-) -> impl IntoResponse {
+) -> Result<Json<serde_json::Value>, ApiError> {
+    record_user_id(&user_id_str);
     tracing::info!("Validating user_id: '{}' with context: {:?}", user_id_str, query_params.context);
-    let connection = &mut establish_db_connection();
+    run_db(&pool, move |connection| {
     
-    #[cfg(feature = "training_direct_entry")]
-    {
-        let user_record_result = registered_users::table
-            .filter(registered_users::user_id.eq(&user_id_str))
-            .select((registered_users::id, registered_users::username))
-            .first::<(i32, Option<String>)>(connection)
-            .optional(); // Call optional() on the Result
-
-        let user_record = match user_record_result {
-            Ok(record) => record,
-            Err(e) => {
-                tracing::error!("Database error checking user_id '{}': {:?}", user_id_str, e);
-                return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": "Database error"}))).into_response();
-            }
-        };
+        #[cfg(feature = "training_direct_entry")]
+        {
+            let user_record = registered_users::table
+                .filter(registered_users::user_id.eq(&user_id_str))
+                .select((registered_users::id, registered_users::username))
+                .first::<(i32, Option<String>)>(connection)
+                .optional()?;
 
-        match user_record {
-            Some((registered_user_db_id, mut username_option)) => { // User exists
-                tracing::info!("User_id '{}' found in registered_users. DB ID: {}, Username from DB: {:?}", user_id_str, registered_user_db_id, username_option);
-                // Remove the auto-assignment logic since users must set usernames via pretest
-                // if query_params.context.as_deref() == Some("training") {
-                //     if username_option.is_none() {
-                //         tracing::info!("User_id '{}' exists but has NULL username. Context is training. Setting username to user_id.", user_id_str);
-                //         if let Err(e) = diesel::update(registered_users::table.find(registered_user_db_id))
-                //             .set(registered_users::username.eq(&user_id_str))
-                //             .execute(connection)
-                //         {
-                //             tracing::error!("Failed to update username for existing user '{}': {:?}", user_id_str, e);
-                //             return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": "Failed to update username"}))).into_response();
-                //         }
-                //         username_option = Some(user_id_str.clone()); // Update local variable
-                //     }
-                // }
+            match user_record {
+                Some((registered_user_db_id, mut username_option)) => { // User exists
+                    tracing::info!("User_id '{}' found in registered_users. DB ID: {}, Username from DB: {:?}", user_id_str, registered_user_db_id, username_option);
+                    // Remove the auto-assignment logic since users must set usernames via pretest
+                    // if query_params.context.as_deref() == Some("training") {
+                    //     if username_option.is_none() {
+                    //         tracing::info!("User_id '{}' exists but has NULL username. Context is training. Setting username to user_id.", user_id_str);
+                    //         if let Err(e) = diesel::update(registered_users::table.find(registered_user_db_id))
+                    //             .set(registered_users::username.eq(&user_id_str))
+                    //             .execute(connection)
+                    //         {
+                    //             tracing::error!("Failed to update username for existing user '{}': {:?}", user_id_str, e);
+                    //             return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": "Failed to update username"}))).into_response();
+                    //         }
+                    //         username_option = Some(user_id_str.clone()); // Update local variable
+                    //     }
+                    // }
                 
-                // Only allow training access if user has completed pretest (has a username)
-                if query_params.context.as_deref() == Some("training") && username_option.is_none() {
-                    tracing::info!("User_id '{}' trying to access training but has no username. Must complete pretest first.", user_id_str);
-                    return (StatusCode::BAD_REQUEST, Json(json!({ "error": "Please complete pretest first to set a username" }))).into_response();
+                    // Only allow entry to a phase the participant's progress allows
+                    if let Some(context) = query_params.context.as_deref() {
+                        let context: GameMode = context.parse()
+                            .map_err(|e: UnknownGameMode| ApiError::validation("unknown_mode", e.to_string()))?;
+                        let progress = Progress::load(connection, &user_id_str)?;
+                        if let Some((_, phase)) = progress.phase(context) {
+                            if !phase.prerequisites_met || !phase.window_open {
+                                tracing::info!("User_id '{}' trying to access {} early: {:?}.", user_id_str, context, phase.reasons);
+                                return Err(ApiError::forbidden(
+                                    if phase.prerequisites_met { "phase_not_open" } else { "prerequisites_not_met" },
+                                    format!("Cannot start {} yet: {}", context, phase.reasons.join("; "))
+                                ));
+                            }
+                        }
+                    }
+                    Ok(Json(json!({ "user_id": user_id_str, "status": "exists", "username": username_option })))
                 }
-                (StatusCode::OK, Json(json!({ "user_id": user_id_str, "status": "exists", "username": username_option }))).into_response()
-            }
-            None => { // User does not exist
-                tracing::info!("User_id '{}' not found in registered_users.", user_id_str);
-                // Removed auto-creation logic - users must register via email first
-                // if query_params.context.as_deref() == Some("training") {
-                //     tracing::info!("Context is training for new user_id '{}'. Creating user and dummy pretest.", user_id_str);
-                //     // Create new registered_user with username = user_id
-                //     let creation_result = insert_into(registered_users::table)
-                //         .values((
-                //             registered_users::user_id.eq(&user_id_str),
-                //             registered_users::username.eq(&user_id_str),
-                //             // email can be NULL
-                //         ))
-                //         .execute(connection);
+                None => { // User does not exist
+                    tracing::info!("User_id '{}' not found in registered_users.", user_id_str);
+                    // Removed auto-creation logic - users must register via email first
+                    // if query_params.context.as_deref() == Some("training") {
+                    //     tracing::info!("Context is training for new user_id '{}'. Creating user and dummy pretest.", user_id_str);
+                    //     // Create new registered_user with username = user_id
+                    //     let creation_result = insert_into(registered_users::table)
+                    //         .values((
+                    //             registered_users::user_id.eq(&user_id_str),
+                    //             registered_users::username.eq(&user_id_str),
+                    //             // email can be NULL
+                    //         ))
+                    //         .execute(connection);
 
-                // Since we no longer auto-create users, return 404 for non-existent users
-                tracing::info!("User_id '{}' not found. Users must register via email first.", user_id_str);
-                (StatusCode::NOT_FOUND, Json(json!({ "error": "User ID not found. Please register via email first." }))).into_response()
+                    // Since we no longer auto-create users, return 404 for non-existent users
+                    tracing::info!("User_id '{}' not found. Users must register via email first.", user_id_str);
+                    Err(ApiError::not_found("user_not_found", "User ID not found. Please register via email first."))
+                }
             }
         }
-    }
 
-    #[cfg(not(feature = "training_direct_entry"))]
-    {
-        // Standard behavior: simply check if user_id exists.
-        let user_exists_query = registered_users::table
-            .filter(registered_users::user_id.eq(&user_id_str));
+        #[cfg(not(feature = "training_direct_entry"))]
+        {
+            // Standard behavior: simply check if user_id exists.
+            let user_exists_query = registered_users::table
+                .filter(registered_users::user_id.eq(&user_id_str));
         
-        let user_exists_result = diesel::select(diesel::dsl::exists(user_exists_query))
-            .get_result::<bool>(connection);
+            let user_exists = diesel::select(diesel::dsl::exists(user_exists_query))
+                .get_result::<bool>(connection)?;
 
-        match user_exists_result {
-            Ok(true) => {
-                tracing::info!("User_id '{}' found (feature 'training_direct_entry' disabled).
-", user_id_str);
-                (StatusCode::OK, Json(json!({ "user_id": user_id_str, "status": "exists" }))).into_response()
-            }
-            Ok(false) => {
-                tracing::info!("User_id '{}' not found (feature 'training_direct_entry' disabled). Returning 404.
-", user_id_str);
-                (StatusCode::NOT_FOUND, Json(json!({ "error": "User ID not found" }))).into_response()
-            }
-            Err(e) => {
-                tracing::error!("Database error checking user_id '{}' (feature 'training_direct_entry' disabled): {:?}", user_id_str, e);
-                (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": "Database error"}))).into_response()
+            if user_exists {
+                tracing::info!("User_id '{}' found (feature 'training_direct_entry' disabled).", user_id_str);
+                Ok(Json(json!({ "user_id": user_id_str, "status": "exists" })))
+            } else {
+                tracing::info!("User_id '{}' not found (feature 'training_direct_entry' disabled). Returning 404.", user_id_str);
+                Err(ApiError::not_found("user_not_found", "User ID not found"))
             }
         }
-    }
+    })
+    .await
 }
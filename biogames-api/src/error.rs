@@ -0,0 +1,165 @@
+use std::time::Duration;
+
+use axum::{
+    extract::rejection::JsonRejection,
+    http::{header::RETRY_AFTER, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json
+};
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::request_id::current_request_id;
+
+/// The single error type returned by every handler.
+///
+/// Each variant maps to one HTTP status. Variants that can mean several things
+/// carry a `code`, a stable snake_case identifier the client can branch on
+/// (e.g. `submission_too_early`); the message is for humans and may change.
+/// The body always has the shape:
+///
+/// ```json
+/// { "code": "game_not_found", "message": "Game 12 not found", "request_id": "..." }
+/// ```
+#[derive(Debug, Error)]
+pub enum ApiError {
+    #[error("{message}")]
+    NotFound { code: &'static str, message: String },
+
+    #[error("{message}")]
+    Conflict { code: &'static str, message: String },
+
+    #[error("{message}")]
+    Forbidden { code: &'static str, message: String },
+
+    #[error("{message}")]
+    Validation { code: &'static str, message: String },
+
+    #[error("Missing or invalid credentials")]
+    Unauthorized,
+
+    #[error("Too many requests, please slow down")]
+    RateLimited { retry_after: Duration },
+
+    #[error(transparent)]
+    InvalidInput(#[from] validator::ValidationErrors),
+
+    #[error(transparent)]
+    InvalidJson(#[from] JsonRejection),
+
+    #[error("Database error: {0}")]
+    Database(#[from] diesel::result::Error),
+
+    #[error("Database connection unavailable: {0}")]
+    DatabaseUnavailable(#[from] diesel::r2d2::PoolError),
+
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Background task failed: {0}")]
+    BlockingTaskFailed(#[from] tokio::task::JoinError),
+
+    #[error("{0}")]
+    Internal(String),
+}
+
+#[derive(Serialize)]
+pub struct ApiErrorBody {
+    pub code: &'static str,
+    pub message: String,
+    pub request_id: Option<String>,
+}
+
+impl ApiError {
+    pub fn not_found(code: &'static str, message: impl Into<String>) -> Self {
+        ApiError::NotFound { code, message: message.into() }
+    }
+
+    pub fn conflict(code: &'static str, message: impl Into<String>) -> Self {
+        ApiError::Conflict { code, message: message.into() }
+    }
+
+    pub fn forbidden(code: &'static str, message: impl Into<String>) -> Self {
+        ApiError::Forbidden { code, message: message.into() }
+    }
+
+    pub fn validation(code: &'static str, message: impl Into<String>) -> Self {
+        ApiError::Validation { code, message: message.into() }
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        ApiError::Internal(message.into())
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::NotFound { .. } => StatusCode::NOT_FOUND,
+            ApiError::Conflict { .. } => StatusCode::CONFLICT,
+            ApiError::Forbidden { .. } => StatusCode::FORBIDDEN,
+            ApiError::Validation { .. } | ApiError::InvalidInput(_) | ApiError::InvalidJson(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Database(diesel::result::Error::NotFound) => StatusCode::NOT_FOUND,
+            ApiError::DatabaseUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Database(_) | ApiError::Io(_) | ApiError::BlockingTaskFailed(_) | ApiError::Internal(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::NotFound { code, .. }
+            | ApiError::Conflict { code, .. }
+            | ApiError::Forbidden { code, .. }
+            | ApiError::Validation { code, .. } => code,
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::RateLimited { .. } => "rate_limited",
+            ApiError::InvalidInput(_) => "invalid_input",
+            ApiError::InvalidJson(_) => "invalid_json",
+            ApiError::Database(diesel::result::Error::NotFound) => "not_found",
+            ApiError::Database(_) => "database_error",
+            ApiError::DatabaseUnavailable(_) => "database_unavailable",
+            ApiError::Io(_) => "io_error",
+            ApiError::BlockingTaskFailed(_) | ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    /// Message shown to the client. Server-side failures are logged in full
+    /// but only described generically, so no SQL or paths leak out.
+    fn public_message(&self) -> String {
+        match self {
+            ApiError::InvalidInput(_) => format!("Input validation error: [{self}]").replace('\n', ", "),
+            ApiError::Database(diesel::result::Error::NotFound) => "Not found".to_string(),
+            ApiError::DatabaseUnavailable(_) => "Server is busy, please try again shortly".to_string(),
+            ApiError::Database(_) | ApiError::Io(_) | ApiError::BlockingTaskFailed(_) | ApiError::Internal(_) => {
+                "Internal Server Error".to_string()
+            }
+            _ => self.to_string(),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        if status.is_server_error() {
+            tracing::error!(code = self.code(), "{}", self);
+        } else {
+            tracing::debug!(code = self.code(), "{}", self);
+        }
+
+        let body = ApiErrorBody {
+            code: self.code(),
+            message: self.public_message(),
+            request_id: current_request_id(),
+        };
+        let mut response = (status, Json(body)).into_response();
+        if let ApiError::RateLimited { retry_after } = self {
+            // Whole seconds, rounded up so a client that waits exactly this long succeeds.
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(seconds.max(1)));
+        }
+        response
+    }
+}
@@ -0,0 +1,86 @@
+use chrono::{DateTime, Utc};
+use diesel::{
+    dsl::count,
+    prelude::*,
+    sql_query,
+    sql_types::{Integer, Timestamptz, Varchar}
+};
+
+use crate::{
+    error::ApiError,
+    models::{EndReason, Game, GameMode},
+    schema::{challenges, games}
+};
+
+/// How far through its challenges a game is.
+#[derive(Debug, Clone, Copy)]
+pub struct ChallengeProgress {
+    pub completed: i32,
+    pub total: i32,
+}
+
+pub fn challenge_progress(connection: &mut PgConnection, game_id: i32) -> Result<ChallengeProgress, ApiError> {
+    let (completed, total) = challenges::table
+        .filter(challenges::game_id.eq(game_id))
+        .select((count(challenges::guess), count(challenges::id)))
+        .get_result::<(i64, i64)>(connection)?;
+    Ok(ChallengeProgress { completed: completed as i32, total: total as i32 })
+}
+
+/// The user's most recent game that has not been finished or quit, optionally
+/// restricted to one mode.
+pub fn unfinished_game(
+    connection: &mut PgConnection,
+    user_id: &str,
+    mode: Option<GameMode>
+) -> Result<Option<Game>, ApiError> {
+    let mut query = games::table
+        .filter(games::user_id.eq(user_id))
+        .filter(games::finished_at.is_null())
+        .order(games::id.desc())
+        .select(Game::as_select())
+        .into_boxed();
+    if let Some(mode) = mode {
+        query = query.filter(games::game_type.eq(mode));
+    }
+    Ok(query.first(connection).optional()?)
+}
+
+/// Closes an open game early, salvaging what was played: the score and time
+/// are summed over the answered challenges and the unanswered ones are
+/// removed. Returns false (and changes nothing) if the game was already
+/// finished. Runs in its own transaction.
+pub fn finalize_game(
+    connection: &mut PgConnection,
+    game_id: i32,
+    finished_at: DateTime<Utc>,
+    reason: EndReason
+) -> Result<bool, ApiError> {
+    connection.transaction(|connection| {
+        let updated = sql_query(r#"
+            UPDATE games
+            SET
+                score = COALESCE(score, (SELECT SUM(points) FROM challenges WHERE game_id = $1 AND points IS NOT NULL)),
+                time_taken_ms = COALESCE(time_taken_ms, (SELECT FLOOR(EXTRACT(epoch FROM SUM(submitted_at - started_at)) * 1000)
+                                            FROM challenges
+                                            WHERE game_id = $1 AND submitted_at IS NOT NULL AND started_at IS NOT NULL)),
+                finished_at = $2,
+                end_reason = $3
+            WHERE id = $1 AND finished_at IS NULL
+            "#)
+            .bind::<Integer, _>(game_id)
+            .bind::<Timestamptz, _>(finished_at)
+            .bind::<Varchar, _>(reason)
+            .execute(connection)?;
+
+        if updated == 0 {
+            return Ok(false);
+        }
+
+        diesel::delete(challenges::table)
+            .filter(challenges::game_id.eq(game_id))
+            .filter(challenges::guess.is_null())
+            .execute(connection)?;
+        Ok(true)
+    })
+}
@@ -1,16 +1,130 @@
-use std::env;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use diesel::{Connection, PgConnection};
-use dotenvy::dotenv;
+use axum::extract::FromRef;
+use diesel::{
+    r2d2::{ConnectionManager, Pool, PoolError, PooledConnection},
+    PgConnection
+};
+use metrics_exporter_prometheus::PrometheusHandle;
+use diesel_migrations::{embed_migrations, EmbeddedMigrations};
 
+use crate::{config::AppConfig, error::ApiError, rate_limit::RateLimiter};
+
+pub mod auth;
+pub mod config;
+pub mod core_sets;
+pub mod cors;
 pub mod endpoints;
+pub mod error;
+pub mod games;
 pub mod models;
+pub mod progression;
+pub mod protocol;
+pub mod rate_limit;
+pub mod request_id;
+pub mod sampling;
 pub mod schema;
 pub mod scoring;
+pub mod selection;
+pub mod shutdown;
+pub mod sweeper;
+pub mod telemetry;
+pub mod test_forms;
+pub mod tls;
+
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("./migrations");
+
+pub type PgPool = Pool<ConnectionManager<PgConnection>>;
+pub type PgPooledConnection = PooledConnection<ConnectionManager<PgConnection>>;
+
+/// State shared by every route. Handlers extract just the part they need,
+/// e.g. `State(pool): State<PgPool>` or `State(config): State<Arc<AppConfig>>`.
+#[derive(Clone)]
+pub struct AppState {
+    pub pool: PgPool,
+    pub config: Arc<AppConfig>,
+    pub metrics: PrometheusHandle,
+    pub rate_limiter: RateLimiter
+}
+
+impl FromRef<AppState> for PgPool {
+    fn from_ref(state: &AppState) -> Self {
+        state.pool.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<AppConfig> {
+    fn from_ref(state: &AppState) -> Self {
+        state.config.clone()
+    }
+}
+
+impl FromRef<AppState> for PrometheusHandle {
+    fn from_ref(state: &AppState) -> Self {
+        state.metrics.clone()
+    }
+}
+
+impl FromRef<AppState> for RateLimiter {
+    fn from_ref(state: &AppState) -> Self {
+        state.rate_limiter.clone()
+    }
+}
+
+/// Builds the shared connection pool, opening the initial connections up front
+/// so a bad `DATABASE_URL` is caught at startup rather than on the first request.
+pub fn establish_db_pool(
+    db_url: &str,
+    max_size: u32,
+    connection_timeout: Duration
+) -> Result<PgPool, PoolError> {
+    let manager = ConnectionManager::<PgConnection>::new(db_url);
+    Pool::builder()
+        .max_size(max_size)
+        .connection_timeout(connection_timeout)
+        .build(manager)
+}
+
+/// Checks a connection out of the pool, waiting at most the configured
+/// checkout timeout. An exhausted pool is reported as 503 to the client.
+pub fn get_db_connection(pool: &PgPool) -> Result<PgPooledConnection, ApiError> {
+    pool.get().map_err(|e| {
+        tracing::error!("Failed to check out a database connection: {}", e);
+        ApiError::DatabaseUnavailable(e)
+    })
+}
+
+/// Runs synchronous Diesel work on tokio's blocking thread pool so a slow
+/// query (or the pool checkout itself) never stalls the async workers.
+/// Every endpoint goes through this rather than touching the pool directly.
+/// The closure runs inside the caller's span, so its events (and any ids it
+/// records) stay attached to the request. Pool wait and query time are
+/// recorded separately so a saturated pool is distinguishable from slow SQL.
+pub async fn run_db<F, T>(pool: &PgPool, f: F) -> Result<T, ApiError>
+where
+    F: FnOnce(&mut PgConnection) -> Result<T, ApiError> + Send + 'static,
+    T: Send + 'static
+{
+    let pool = pool.clone();
+    let span = tracing::Span::current();
+    tokio::task::spawn_blocking(move || {
+        span.in_scope(|| {
+            let checkout = Instant::now();
+            let mut connection = get_db_connection(&pool)?;
+            metrics::histogram!("biogames_db_pool_wait_seconds").record(checkout.elapsed().as_secs_f64());
 
-pub fn establish_db_connection() -> PgConnection {
-    dotenv().ok();
-    let db_url = env::var("DATABASE_URL").expect("DATABASE_URL not set");
-    PgConnection::establish(&db_url)
-        .unwrap_or_else(|_| panic!("Error connecting to {}", db_url))
+            let query = Instant::now();
+            let result = f(&mut connection);
+            // Business rejections (not found, conflict, ...) are successful queries.
+            let outcome = match &result {
+                Err(e) if e.status().is_server_error() => "error",
+                _ => "ok",
+            };
+            metrics::histogram!("biogames_db_query_duration_seconds", "outcome" => outcome)
+                .record(query.elapsed().as_secs_f64());
+            result
+        })
+    })
+    .await?
 }
@@ -1,21 +1,14 @@
-mod scoring;
-
 use std::net::SocketAddr;
+use std::process;
+use std::sync::Arc;
 
-use axum::Router;
+use axum::{middleware, Router};
 use axum::routing::{get, post};
 
-use axum_server::Server;
-use axum::http::Method;
-
-use tower_http::cors::Any;
-use tower_http::cors::CorsLayer;
+use axum_server::{Handle, Server};
 
-use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
-use tracing_subscriber::{
-    layer::SubscriberExt,
-    util::SubscriberInitExt
-};
+use diesel_migrations::MigrationHarness;
+use dotenvy::dotenv;
 
 use biogames_api::{
     endpoints::{
@@ -34,32 +27,94 @@ use biogames_api::{
         get_preview_core_id::*,
         get_her2_core_image::*,
         analytics::*,
+        health::*,
+        get_metrics::*,
+        get_active_game::*,
+        get_progress::*,
+        admin_core_sets::*,
+        admin_games::*,
     },
-    establish_db_connection
+    config::AppConfig,
+    cors::cors_layer,
+    establish_db_pool,
+    protocol::games_over_attempt_limit,
+    shutdown::spawn_graceful_shutdown,
+    sweeper::spawn_abandoned_game_sweeper,
+    test_forms::overlapping_forms,
+    telemetry::{init_tracing, install_metrics_recorder, trace_layer, track_http_metrics},
+    rate_limit::{limit_by_ip, RateLimiter},
+    request_id::request_id,
+    tls::{load_rustls_config, serve_http_redirect, spawn_certificate_reloader},
+    AppState, MIGRATIONS
 };
 
 #[tokio::main]
 async fn main() {
-    let mut connection = establish_db_connection();
-    const MIGRATIONS: EmbeddedMigrations = embed_migrations!("./migrations");
-    connection.run_pending_migrations(MIGRATIONS)
-        .unwrap_or_else(|_| panic!("Error running migrations"));
+    dotenv().ok();
+    let config = AppConfig::load().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
 
-    tracing_subscriber::registry()
-        .with(tracing_subscriber::EnvFilter::try_from_default_env()
-            .unwrap_or_else(|_| "biogames_api=debug,tower_http=debug".into()))
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+    init_tracing(&config.logging);
 
     // Log a message to confirm the logger is working
     tracing::debug!("Tracing initialized");
+    config.log_summary();
+
+    let pool = establish_db_pool(&config.database.url, config.database.pool_max_size, config.db_pool_timeout())
+        .unwrap_or_else(|e| {
+            tracing::error!("Error connecting to the database: {}", e);
+            process::exit(1);
+        });
 
-    let cors = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
-        .allow_headers(Any);
+    let mut connection = pool.get().unwrap_or_else(|e| {
+        tracing::error!("Error checking out a migration connection: {}", e);
+        process::exit(1);
+    });
+    if let Err(e) = connection.run_pending_migrations(MIGRATIONS) {
+        tracing::error!("Error running migrations: {}", e);
+        process::exit(1);
+    }
+    match games_over_attempt_limit(&mut connection) {
+        Ok(over_limit) => {
+            for entry in &over_limit {
+                tracing::warn!(
+                    "User {} has {} {} games but the protocol allows {} (game ids {:?}); review and remove the extras",
+                    entry.user_id, entry.games, entry.game_type, entry.max_attempts, entry.game_ids
+                );
+            }
+        }
+        Err(e) => tracing::warn!("Could not check for games over the attempt limit: {}", e),
+    }
+    match overlapping_forms(&mut connection) {
+        Ok(overlaps) => {
+            for overlap in &overlaps {
+                tracing::warn!(
+                    "Test forms {} and {} of protocol {} share {} core(s); parallel forms should be disjoint",
+                    overlap.form_a, overlap.form_b, overlap.protocol, overlap.shared_cores
+                );
+            }
+        }
+        Err(e) => tracing::warn!("Could not check test forms for shared cores: {}", e),
+    }
+    drop(connection);
 
-    let app = Router::new()
+    let addr = config.socket_addr();
+    let tls = config.tls.clone();
+    let shutdown_grace = config.shutdown_grace();
+    let metrics = install_metrics_recorder().unwrap_or_else(|e| {
+        tracing::error!("Failed to install the metrics recorder: {}", e);
+        process::exit(1);
+    });
+    spawn_abandoned_game_sweeper(pool.clone(), config.sweeper.clone());
+    let rate_limiter = RateLimiter::in_memory(config.rate_limit.clone());
+    let state = AppState { pool, config: Arc::new(config), metrics, rate_limiter };
+
+    let cors = cors_layer(&state.config.cors);
+
+    // Routes the browser client calls, possibly from another origin.
+    let public = Router::new()
         .route("/games", post(create_game))
         .route("/games/:id", get(get_game))
         .route("/games/:id/challenge", get(get_current_challenge))
@@ -72,30 +127,68 @@ async fn main() {
         .route("/check-username/:user_id", get(check_username))
         .route("/generate-user-id", post(generate_user_id))
         .route("/register-with-username", post(register_with_username))
+        .route("/users/:user_id/active-game", get(get_active_game))
+        .route("/users/:user_id/progress", get(get_progress))
         .route("/api/preview_core_id", get(get_preview_core_id))
         .route("/api/her2_core_images/:her2_core_id", get(get_her2_core_image))
+        .layer(middleware::from_fn_with_state(state.clone(), limit_by_ip))
+        .layer(cors);
+
+    // Operator routes. Mounted outside the CORS layer, so cross-origin
+    // preflights fail and browsers never expose the responses to other sites.
+    let internal = Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/metrics", get(get_metrics))
         .route("/analytics/games.csv", get(games_csv))
         .route("/analytics/challenges.csv", get(challenges_csv))
         .route("/analytics/registered_users.csv", get(registered_users_csv))
         .route("/analytics/email_registry.csv", get(email_registry_csv))
-        .layer(cors);
+        .route("/admin/core-sets", get(list_core_sets).post(create_core_set))
+        .route("/admin/core-sets/:name", get(get_core_set))
+        .route("/admin/core-sets/:name/members", post(add_core_set_members))
+        .route("/admin/core-sets/:name/freeze", post(freeze_core_set))
+        .route("/admin/games/:id/selection", get(audit_game_selection));
+
+    let app = public
+        .merge(internal)
+        .layer(middleware::from_fn(track_http_metrics))
+        .layer(trace_layer())
+        .layer(middleware::from_fn(request_id))
+        .with_state(state);
+
+
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+    let handle = Handle::new();
+    spawn_graceful_shutdown(handle.clone(), shutdown_grace);
+
+    let served = if tls.enabled {
+        let rustls = load_rustls_config(&tls).await.unwrap_or_else(|e| {
+            tracing::error!("Failed to load TLS certificate {} / key {}: {}", tls.cert_path.display(), tls.key_path.display(), e);
+            process::exit(1);
+        });
+        spawn_certificate_reloader(rustls.clone(), tls.clone());
+
+        if let Some(http_port) = tls.redirect_http_port {
+            tokio::spawn(serve_http_redirect(SocketAddr::new(addr.ip(), http_port), addr.port()));
+        }
 
+        tracing::debug!("listening on https://{}", addr);
+        axum_server::bind_rustls(addr, rustls)
+            .handle(handle)
+            .serve(app)
+            .await
+    } else {
+        tracing::debug!("listening on {}", addr);
+        Server::bind(addr)
+            .handle(handle)
+            .serve(app)
+            .await
+    };
 
-    // let config = RustlsConfig::from_pem_file(
-    //     PathBuf::from("cert").join("cert.pem"),
-    //     PathBuf::from("cert").join("key.pem")
-    // )
-    // .await
-    // .unwrap();
-
-    let addr = SocketAddr::from(([0, 0, 0, 0], 3001));
-    tracing::debug!("listening on {}", addr);
-    Server::bind(addr)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
-    // axum_server::bind_rustls(addr, config)
-    //     .serve(app.into_make_service())
-    //     .await
-    //     .unwrap();
+    if let Err(e) = served {
+        tracing::error!("Server on {} failed: {}", addr, e);
+        process::exit(1);
+    }
+    tracing::info!("Server stopped");
 }
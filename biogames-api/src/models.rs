@@ -5,11 +5,127 @@ use axum::{
     response::{IntoResponse, Response},
     Json};
 use chrono::{DateTime, Utc};
-use diesel::{AsChangeset, Insertable, Queryable, QueryableByName, Selectable};
+use diesel::{
+    deserialize::{self, FromSql, FromSqlRow},
+    expression::AsExpression,
+    pg::{Pg, PgValue},
+    serialize::{self, Output, ToSql},
+    sql_types::Varchar,
+    AsChangeset, Insertable, Queryable, QueryableByName, Selectable
+};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use thiserror::Error;
+use std::{fmt, str::FromStr};
 use validator::Validate;
 
+use crate::error::ApiError;
+
+/// The kind of game, stored in `games.game_type` (and the protocol tables).
+/// The column carries a CHECK constraint with the same values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, AsExpression, FromSqlRow, Serialize)]
+#[diesel(sql_type = Varchar)]
+#[serde(rename_all = "lowercase")]
+pub enum GameMode {
+    Pretest,
+    Training,
+    Posttest,
+    /// Practice on cores the player previously mis-scored. Never on the leaderboard.
+    Review,
+}
+
+impl GameMode {
+    pub const ALL: [GameMode; 4] = [GameMode::Pretest, GameMode::Training, GameMode::Posttest, GameMode::Review];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            GameMode::Pretest => "pretest",
+            GameMode::Training => "training",
+            GameMode::Posttest => "posttest",
+            GameMode::Review => "review",
+        }
+    }
+}
+
+impl fmt::Display for GameMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownGameMode(pub String);
+
+impl fmt::Display for UnknownGameMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let expected: Vec<&str> = GameMode::ALL.iter().map(|mode| mode.as_str()).collect();
+        write!(f, "Unknown game mode '{}', expected one of: {}", self.0, expected.join(", "))
+    }
+}
+
+impl std::error::Error for UnknownGameMode {}
+
+impl FromStr for GameMode {
+    type Err = UnknownGameMode;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        GameMode::ALL
+            .into_iter()
+            .find(|mode| mode.as_str() == value)
+            .ok_or_else(|| UnknownGameMode(value.to_string()))
+    }
+}
+
+impl ToSql<Varchar, Pg> for GameMode {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        <str as ToSql<Varchar, Pg>>::to_sql(self.as_str(), out)
+    }
+}
+
+impl FromSql<Varchar, Pg> for GameMode {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        Ok(<String as FromSql<Varchar, Pg>>::from_sql(bytes)?.parse()?)
+    }
+}
+
+/// Why a game stopped, stored in `games.end_reason` (NULL while it is open).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsExpression, FromSqlRow, Serialize)]
+#[diesel(sql_type = Varchar)]
+#[serde(rename_all = "lowercase")]
+pub enum EndReason {
+    /// Every challenge was answered.
+    Completed,
+    /// The player quit part way through.
+    Quit,
+    /// Finalized by the sweeper after sitting idle.
+    Abandoned,
+}
+
+impl EndReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EndReason::Completed => "completed",
+            EndReason::Quit => "quit",
+            EndReason::Abandoned => "abandoned",
+        }
+    }
+}
+
+impl ToSql<Varchar, Pg> for EndReason {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        <str as ToSql<Varchar, Pg>>::to_sql(self.as_str(), out)
+    }
+}
+
+impl FromSql<Varchar, Pg> for EndReason {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        match <String as FromSql<Varchar, Pg>>::from_sql(bytes)?.as_str() {
+            "completed" => Ok(EndReason::Completed),
+            "quit" => Ok(EndReason::Quit),
+            "abandoned" => Ok(EndReason::Abandoned),
+            other => Err(format!("Unknown end reason '{}'", other).into()),
+        }
+    }
+}
+
 #[derive(Debug, Queryable, QueryableByName, Selectable, Insertable, AsChangeset)]
 #[diesel(table_name = crate::schema::games)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
@@ -21,8 +137,18 @@ pub struct Game {
     pub score: Option<i32>,
     pub max_score: i32,
     pub time_taken_ms: Option<i32>,
-    pub game_type: String,
-    pub user_id: String
+    pub game_type: GameMode,
+    pub user_id: String,
+    pub end_reason: Option<EndReason>,
+    pub time_limit_secs: Option<i32>,
+    pub core_set: Option<String>,
+    pub sampling: Option<String>,
+    pub seed: Option<i64>,
+    pub initial_core_id: Option<i32>,
+    pub sampling_counts: Option<Vec<i32>>,
+    pub test_form: Option<String>,
+    pub no_repeat: bool,
+    pub reshow_missed_rate: Option<f64>
 }
 
 #[derive(Debug, Queryable, QueryableByName, Selectable, Insertable, AsChangeset)]
@@ -37,7 +163,8 @@ pub struct Challenge {
     pub guess: Option<i32>,
     pub started_at: Option<DateTime<Utc>>,
     pub submitted_at: Option<DateTime<Utc>>,
-    pub points: Option<i32>
+    pub points: Option<i32>,
+    pub timed_out: bool
 }
 
 #[derive(Debug, Queryable, Selectable, Insertable, AsChangeset)]
@@ -66,6 +193,77 @@ pub struct NewRegisteredUser {
     pub username: Option<String>
 }
 
+#[derive(Debug, Queryable, Selectable)]
+#[diesel(table_name = crate::schema::study_protocols)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct StudyProtocol {
+    pub id: i32,
+    pub name: String,
+    pub description: Option<String>,
+    pub active: bool,
+    pub created_at: DateTime<Utc>
+}
+
+#[derive(Debug, Queryable, Selectable)]
+#[diesel(table_name = crate::schema::study_phases)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct StudyPhase {
+    pub id: i32,
+    pub protocol_id: i32,
+    pub game_type: GameMode,
+    pub position: i32,
+    pub max_attempts: Option<i32>,
+    pub challenges_per_game: i32,
+    pub core_set: String,
+    pub resume_unfinished: bool,
+    pub opens_at: Option<DateTime<Utc>>,
+    pub closes_at: Option<DateTime<Utc>>,
+    pub unlocks_after: Option<GameMode>,
+    pub unlocks_after_days: Option<i32>,
+    pub open_for_days: Option<i32>,
+    pub time_limit_secs: Option<i32>,
+    pub draw: String,
+    pub sampling: String,
+    pub sampling_counts: Option<Vec<i32>>,
+    pub fixed_seed: Option<i64>,
+    pub uses_test_form: bool,
+    pub no_repeat: bool,
+    pub reshow_missed_rate: f64
+}
+
+#[derive(Debug, Queryable, Selectable)]
+#[diesel(table_name = crate::schema::study_phase_prerequisites)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct StudyPhasePrerequisite {
+    pub id: i32,
+    pub phase_id: i32,
+    pub required_game_type: GameMode,
+    pub min_games: i32
+}
+
+#[derive(Debug, Queryable, Selectable)]
+#[diesel(table_name = crate::schema::core_sets)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct CoreSet {
+    pub id: i32,
+    pub name: String,
+    pub description: Option<String>,
+    pub frozen: bool,
+    pub created_at: DateTime<Utc>,
+    pub frozen_at: Option<DateTime<Utc>>
+}
+
+#[derive(Debug, Clone, Queryable, Selectable)]
+#[diesel(table_name = crate::schema::test_forms)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct TestForm {
+    pub id: i32,
+    pub protocol_id: i32,
+    pub name: String,
+    pub position: i32,
+    pub core_set: String
+}
+
 #[derive(Clone, Copy, Default)]
 pub struct ValidatedRequest<T>(pub T);
 
@@ -76,7 +274,7 @@ where
     S: Send + Sync,
     Json<T>: FromRequest<S, Rejection = JsonRejection>
 {
-    type Rejection = ServerError;
+    type Rejection = ApiError;
 
     async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
         let Json(value) = Json::<T>::from_request(req, state).await?;
@@ -85,28 +283,6 @@ where
     }
 }
 
-#[derive(Debug, Error)]
-pub enum ServerError {
-    #[error(transparent)]
-    ValidationError(#[from] validator::ValidationErrors),
-
-    #[error(transparent)]
-    AxumJsonRejection(#[from] JsonRejection)
-}
-
-impl IntoResponse for ServerError {
-    fn into_response(self) -> Response {
-        match self {
-            ServerError::ValidationError(_) => {
-                let message = format!("Input validation error: [{self}]").replace('\n', ", ");
-                (StatusCode::BAD_REQUEST, message)
-            }
-            ServerError::AxumJsonRejection(_) => (StatusCode::BAD_REQUEST, self.to_string()),
-        }
-        .into_response()
-    }
-}
-
 #[derive(Deserialize, Validate)]
 pub struct CreateGameRequest {
     #[validate(length(min = 1, max = 32, message = "Must be between 1 and 32 characters"))]
@@ -119,14 +295,20 @@ pub struct GameResponse {
     pub id: i32,
     pub user: Option<String>,
     pub results: Option<GameResultsResponse>,
-    pub total_points: Option<i32>
+    pub total_points: Option<i32>,
+    pub completed_challenges: Option<i32>,
+    pub total_challenges: Option<i32>,
+    /// Set by game creation: true when an existing game was returned.
+    pub resumed: Option<bool>
 }
 
 #[derive(Serialize)]
 pub struct GameCountResponse {
     pub pretest: i64,
     pub posttest: i64,
-    pub training: i64
+    pub training: i64,
+    /// Game types the active protocol lets this user start right now.
+    pub available: Vec<GameMode>
 }
 
 #[derive(Serialize)]
@@ -134,7 +316,10 @@ pub struct GameResultsResponse {
     pub severe_mistakes: Vec<GameResultResponse>,
     pub moderate_mistakes: Vec<GameResultResponse>,
     pub mild_mistakes: Vec<GameResultResponse>,
-    pub correct: Vec<GameResultResponse>
+    pub correct: Vec<GameResultResponse>,
+    /// Answered after the deadline; scored with the timeout penalty whatever
+    /// the guess.
+    pub timed_out: Vec<GameResultResponse>
 }
 
 #[derive(Serialize)]
@@ -144,7 +329,8 @@ pub struct GameResultResponse {
     pub guess: i32,
     pub correct_score: i32,
     pub seconds: f64,
-    pub points: i32
+    pub points: i32,
+    pub timed_out: bool
 }
 
 impl IntoResponse for GameResponse {
@@ -158,6 +344,23 @@ pub struct CurrentChallengeResponse {
     pub id: Option<i32>,
     pub core_id: Option<i32>,
     pub completed_challenges: i32,
+    pub total_challenges: i32,
+    /// Per-challenge limit for timed games; the rest are `None` when untimed.
+    pub time_limit_ms: Option<i64>,
+    /// Set once the challenge's core has been served and the clock is running.
+    pub deadline: Option<DateTime<Utc>>,
+    /// The full limit until the core is served, then time left before the
+    /// deadline (never negative).
+    pub remaining_ms: Option<i64>
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ActiveGameResponse {
+    pub id: i32,
+    pub game_type: GameMode,
+    pub user: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub completed_challenges: i32,
     pub total_challenges: i32
 }
 
@@ -167,6 +370,65 @@ impl IntoResponse for CurrentChallengeResponse {
     }
 }
 
+#[derive(Deserialize, Validate)]
+pub struct CreateCoreSetRequest {
+    #[validate(length(min = 1, max = 64, message = "Must be between 1 and 64 characters"))]
+    pub name: String,
+    pub description: Option<String>,
+    #[validate(length(min = 1, message = "Must list at least one core"))]
+    pub core_ids: Vec<i32>,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct AddCoreSetMembersRequest {
+    #[validate(length(min = 1, message = "Must list at least one core"))]
+    pub core_ids: Vec<i32>,
+}
+
+#[derive(Serialize)]
+pub struct CoreSetSummaryResponse {
+    pub name: String,
+    pub description: Option<String>,
+    pub frozen: bool,
+    pub created_at: DateTime<Utc>,
+    pub frozen_at: Option<DateTime<Utc>>,
+    pub size: i64
+}
+
+#[derive(Serialize)]
+pub struct CoreSetResponse {
+    pub name: String,
+    pub description: Option<String>,
+    pub frozen: bool,
+    pub created_at: DateTime<Utc>,
+    pub frozen_at: Option<DateTime<Utc>>,
+    pub core_ids: Vec<i32>
+}
+
+/// A game's challenges next to what its draw yields when re-derived from
+/// the stored inputs and `seed`.
+#[derive(Serialize)]
+pub struct SelectionAuditResponse {
+    pub game_id: i32,
+    pub game_type: GameMode,
+    pub core_set: String,
+    /// Unfrozen sets may have gained cores since the game was drawn, which
+    /// changes the result.
+    pub core_set_frozen: bool,
+    pub sampling: Option<String>,
+    pub seed: i64,
+    /// The seed on the games row; `seed` differs when one was given.
+    pub stored_seed: Option<i64>,
+    pub expected_core_ids: Vec<i32>,
+    pub actual_core_ids: Vec<i32>,
+    /// Quit and abandoned games lose their unanswered challenges, so
+    /// `actual_core_ids` is then a prefix of `expected_core_ids`.
+    pub truncated: bool,
+    /// `actual_core_ids` is `expected_core_ids` or, if truncated, a prefix
+    /// of it.
+    pub matches: bool
+}
+
 #[derive(Deserialize, Validate)]
 pub struct SubmitChallengeRequest {
     #[validate(range(min = 0, max = 3, message = "Must be between 0 and 3"))]
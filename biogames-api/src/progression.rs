@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::Serialize;
+
+use crate::{
+    error::ApiError,
+    games::challenge_progress,
+    models::{ActiveGameResponse, Game, GameMode},
+    protocol::{GameCounts, Phase, Protocol, WindowState},
+    schema::{games, registered_users}
+};
+
+/// Where a participant stands in one phase of the protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PhaseStatus {
+    /// Prerequisites not met yet.
+    Locked,
+    /// Prerequisites met but the phase's window hasn't opened yet.
+    Scheduled,
+    /// The phase's window has closed.
+    Closed,
+    /// A new game can be started.
+    Available,
+    /// There is an unfinished game of this phase.
+    InProgress,
+    /// Every allowed attempt has been used.
+    Done,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PhaseProgress {
+    pub game_type: GameMode,
+    pub status: PhaseStatus,
+    pub played: i64,
+    pub max_attempts: Option<i64>,
+    pub unfinished_game_id: Option<i32>,
+    /// When the phase's window opens, if it hasn't yet and the time is known.
+    pub unlocks_at: Option<DateTime<Utc>>,
+    /// When the phase's window closes, if it is open and closes at all.
+    pub closes_at: Option<DateTime<Utc>>,
+    /// Why a new game of this phase can't be started right now; empty when it can.
+    pub reasons: Vec<String>,
+    #[serde(skip)]
+    pub prerequisites_met: bool,
+    #[serde(skip)]
+    pub limit_reached: bool,
+    #[serde(skip)]
+    pub window_open: bool,
+}
+
+/// The participant's overall position in the study.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ParticipantState {
+    /// Registered by email but no username yet, so no game can be created.
+    NeedsUsername,
+    /// Has an unfinished game; `active_game` says which.
+    InProgress,
+    /// Can start a game of `next`.
+    Available,
+    /// Nothing can be started until a phase's window opens; see `unlocks_at`.
+    Waiting,
+    /// Nothing can be started right now and the study isn't complete; see `reasons`.
+    Blocked,
+    /// Every phase with an attempt limit is done. Unlimited phases may
+    /// still be available.
+    StudyComplete,
+}
+
+/// Everything the rules say about what a participant may do next. This is the
+/// single place those rules are evaluated: game creation, the client's mode
+/// checks and `/users/:user_id/progress` all read it.
+#[derive(Debug, Clone, Serialize)]
+pub struct Progress {
+    pub user_id: String,
+    pub username: Option<String>,
+    #[serde(rename = "protocol")]
+    pub protocol_name: String,
+    pub state: ParticipantState,
+    /// The phase the participant should play next, if any.
+    pub next: Option<GameMode>,
+    /// When the next scheduled phase opens, if one is waiting on its window.
+    pub unlocks_at: Option<DateTime<Utc>>,
+    pub active_game: Option<ActiveGameResponse>,
+    pub reasons: Vec<String>,
+    pub phases: Vec<PhaseProgress>,
+    #[serde(skip)]
+    pub protocol: Protocol,
+}
+
+impl Progress {
+    pub fn load(connection: &mut PgConnection, user_id: &str) -> Result<Progress, ApiError> {
+        let username = registered_users::table
+            .filter(registered_users::user_id.eq(user_id))
+            .select(registered_users::username)
+            .first::<Option<String>>(connection)
+            .optional()?
+            .ok_or_else(|| ApiError::not_found(
+                "user_not_found",
+                format!("No registered user found for User ID '{}'", user_id)
+            ))?;
+
+        let protocol = Protocol::load_active(connection)?;
+        let counts = GameCounts::load(connection, user_id)?;
+        let first_finished = first_finished(connection, user_id)?;
+        let now = Utc::now();
+        let unfinished = games::table
+            .filter(games::user_id.eq(user_id))
+            .filter(games::finished_at.is_null())
+            .order(games::id.desc())
+            .select(Game::as_select())
+            .load::<Game>(connection)?;
+
+        let phases: Vec<PhaseProgress> = protocol.phases
+            .iter()
+            .map(|phase| phase_progress(phase, &counts, &unfinished, &first_finished, now))
+            .collect();
+
+        let active_game = match unfinished.into_iter().next() {
+            Some(game) => {
+                let progress = challenge_progress(connection, game.id)?;
+                Some(ActiveGameResponse {
+                    id: game.id,
+                    game_type: game.game_type,
+                    user: game.username,
+                    started_at: game.started_at,
+                    completed_challenges: progress.completed,
+                    total_challenges: progress.total
+                })
+            }
+            None => None,
+        };
+
+        let first_available = phases.iter().find(|phase| phase.status == PhaseStatus::Available);
+        let next_scheduled = phases
+            .iter()
+            .filter(|phase| phase.status == PhaseStatus::Scheduled)
+            .min_by_key(|phase| phase.unlocks_at);
+        // Unlimited phases (like review) are optional extras: the study is
+        // complete once every phase with an attempt limit is done.
+        let mut required = phases.iter().filter(|phase| phase.max_attempts.is_some()).peekable();
+        let study_complete = required.peek().is_some() && required.all(|phase| phase.status == PhaseStatus::Done);
+
+        let (state, next, reasons) = if username.is_none() {
+            (
+                ParticipantState::NeedsUsername,
+                protocol.phases.first().map(|phase| phase.game_type),
+                vec!["Choose a username to start".to_string()]
+            )
+        } else if let Some(active) = &active_game {
+            (ParticipantState::InProgress, Some(active.game_type), Vec::new())
+        } else if study_complete {
+            (ParticipantState::StudyComplete, None, Vec::new())
+        } else if let Some(phase) = first_available {
+            (ParticipantState::Available, Some(phase.game_type), Vec::new())
+        } else if let Some(phase) = next_scheduled {
+            (ParticipantState::Waiting, Some(phase.game_type), phase.reasons.clone())
+        } else {
+            let reasons = phases
+                .iter()
+                .filter(|phase| matches!(phase.status, PhaseStatus::Locked | PhaseStatus::Closed))
+                .flat_map(|phase| phase.reasons.iter().map(move |reason| format!("{}: {}", phase.game_type, reason)))
+                .collect();
+            (ParticipantState::Blocked, None, reasons)
+        };
+
+        let unlocks_at = match state {
+            ParticipantState::StudyComplete => None,
+            _ => next_scheduled.and_then(|phase| phase.unlocks_at),
+        };
+
+        Ok(Progress {
+            user_id: user_id.to_string(),
+            username,
+            protocol_name: protocol.name.clone(),
+            state,
+            next,
+            unlocks_at,
+            active_game,
+            reasons,
+            phases,
+            protocol,
+        })
+    }
+
+    pub fn phase(&self, game_type: GameMode) -> Option<(&Phase, &PhaseProgress)> {
+        let phase = self.protocol.phase(game_type)?;
+        let progress = self.phases.iter().find(|progress| progress.game_type == game_type)?;
+        Some((phase, progress))
+    }
+
+    pub fn played(&self, game_type: GameMode) -> i64 {
+        self.phases.iter().find(|phase| phase.game_type == game_type).map_or(0, |phase| phase.played)
+    }
+
+    /// Phases a new game can be started in right now, in protocol order.
+    pub fn available(&self) -> Vec<GameMode> {
+        self.phases
+            .iter()
+            .filter(|phase| phase.prerequisites_met && !phase.limit_reached && phase.window_open)
+            .map(|phase| phase.game_type)
+            .collect()
+    }
+}
+
+/// When the participant first finished a game of each type; relative phase
+/// windows are anchored on these.
+fn first_finished(connection: &mut PgConnection, user_id: &str) -> Result<HashMap<GameMode, DateTime<Utc>>, ApiError> {
+    let rows = games::table
+        .filter(games::user_id.eq(user_id))
+        .filter(games::finished_at.is_not_null())
+        .group_by(games::game_type)
+        .select((games::game_type, diesel::dsl::min(games::finished_at)))
+        .load::<(GameMode, Option<DateTime<Utc>>)>(connection)?;
+    Ok(rows.into_iter().filter_map(|(game_type, finished_at)| Some((game_type, finished_at?))).collect())
+}
+
+fn phase_progress(
+    phase: &Phase,
+    counts: &GameCounts,
+    unfinished: &[Game],
+    first_finished: &HashMap<GameMode, DateTime<Utc>>,
+    now: DateTime<Utc>
+) -> PhaseProgress {
+    let played = counts.get(phase.game_type);
+    let unfinished_game_id = unfinished.iter().find(|game| game.game_type == phase.game_type).map(|game| game.id);
+
+    let mut reasons: Vec<String> = phase
+        .unmet_prerequisites(counts)
+        .iter()
+        .map(|prerequisite| format!(
+            "requires {} {} game(s), {} played",
+            prerequisite.min_games,
+            prerequisite.game_type,
+            counts.get(prerequisite.game_type)
+        ))
+        .collect();
+    let prerequisites_met = reasons.is_empty();
+    let limit_reached = phase.limit_reached(counts);
+    if limit_reached {
+        reasons.push(format!("all {} allowed game(s) played", played));
+    }
+
+    let window = phase.window.state(first_finished, now);
+    reasons.extend(phase.window.reason(window));
+    let (window_open, unlocks_at, closes_at) = match window {
+        WindowState::Open { closes_at } => (true, None, closes_at),
+        WindowState::NotYetOpen { unlocks_at } => (false, unlocks_at, None),
+        WindowState::Closed { .. } => (false, None, None),
+    };
+
+    let status = if unfinished_game_id.is_some() {
+        PhaseStatus::InProgress
+    } else if limit_reached {
+        PhaseStatus::Done
+    } else if !prerequisites_met {
+        PhaseStatus::Locked
+    } else if let WindowState::NotYetOpen { .. } = window {
+        PhaseStatus::Scheduled
+    } else if let WindowState::Closed { .. } = window {
+        PhaseStatus::Closed
+    } else {
+        PhaseStatus::Available
+    };
+
+    PhaseProgress {
+        game_type: phase.game_type,
+        status,
+        played,
+        max_attempts: phase.max_attempts,
+        unfinished_game_id,
+        unlocks_at,
+        closes_at,
+        reasons,
+        prerequisites_met,
+        limit_reached,
+        window_open,
+    }
+}
@@ -0,0 +1,438 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Duration, Utc};
+use diesel::{
+    dsl::count_star,
+    prelude::*,
+    sql_query,
+    sql_types::{Array, BigInt, Integer, Text, Varchar}
+};
+
+use crate::{
+    error::ApiError,
+    models::{GameMode, StudyPhase, StudyPhasePrerequisite, StudyProtocol, TestForm},
+    sampling::Sampling,
+    schema::{games, study_phase_prerequisites, study_phases, study_protocols, test_forms}
+};
+
+/// How a phase picks challenges from its core set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DrawMethod {
+    /// Uniformly at random.
+    Random,
+    /// Cores in the set the player previously mis-scored.
+    ReviewMistakes,
+}
+
+impl DrawMethod {
+    fn parse(name: &str) -> Option<DrawMethod> {
+        match name {
+            "random" => Some(DrawMethod::Random),
+            "review_mistakes" => Some(DrawMethod::ReviewMistakes),
+            _ => None,
+        }
+    }
+}
+
+/// No-repeat rotation through a phase's core set: cores the participant has
+/// never been served come first, and served ones are only recycled once
+/// those run out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rotation {
+    /// Share of each game filled with cores the participant previously
+    /// mis-scored, on purpose.
+    pub reshow_missed_rate: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Prerequisite {
+    pub game_type: GameMode,
+    pub min_games: i64,
+}
+
+/// One game type a participant may play under the protocol.
+#[derive(Debug, Clone)]
+pub struct Phase {
+    pub id: i32,
+    pub game_type: GameMode,
+    pub position: i32,
+    /// `None` means unlimited.
+    pub max_attempts: Option<i64>,
+    pub challenges_per_game: i32,
+    /// Name of the `core_sets` row challenges are drawn from.
+    pub core_set: String,
+    pub draw: DrawMethod,
+    /// How random draws are spread over the score classes.
+    pub sampling: Sampling,
+    /// Seed every game of the phase is drawn with, so all participants get
+    /// the same cores in the same order. `None` gives each game a fresh seed.
+    pub fixed_seed: Option<i64>,
+    /// Draw from the participant's assigned test form instead of `core_set`.
+    pub uses_test_form: bool,
+    /// Prefer cores the participant hasn't been served; see `Rotation`.
+    pub rotation: Option<Rotation>,
+    pub prerequisites: Vec<Prerequisite>,
+    /// Return the user's unfinished game of this phase instead of creating one.
+    pub resume_unfinished: bool,
+    pub window: PhaseWindow,
+    /// Seconds allowed per challenge; `None` means untimed.
+    pub time_limit_secs: Option<i32>,
+}
+
+/// When a phase may be started. The absolute and the relative window must
+/// both be open; a missing bound doesn't restrict anything.
+#[derive(Debug, Clone, Default)]
+pub struct PhaseWindow {
+    pub opens_at: Option<DateTime<Utc>>,
+    pub closes_at: Option<DateTime<Utc>>,
+    pub relative: Option<RelativeWindow>,
+}
+
+/// A window anchored on when the participant finished their first game of
+/// `after`, e.g. "posttest unlocks 14 days after pretest".
+#[derive(Debug, Clone)]
+pub struct RelativeWindow {
+    pub after: GameMode,
+    pub delay_days: i32,
+    /// `None` means it never closes.
+    pub open_for_days: Option<i32>,
+}
+
+/// Where a phase's window stands for one participant at one moment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WindowState {
+    /// `unlocks_at` is `None` while the anchor game hasn't been finished, so
+    /// the unlock time isn't known yet.
+    NotYetOpen { unlocks_at: Option<DateTime<Utc>> },
+    Open { closes_at: Option<DateTime<Utc>> },
+    Closed { closed_at: DateTime<Utc> },
+}
+
+impl PhaseWindow {
+    /// `first_finished` maps each game type to when the participant first
+    /// finished a game of it.
+    pub fn state(&self, first_finished: &HashMap<GameMode, DateTime<Utc>>, now: DateTime<Utc>) -> WindowState {
+        let mut opens_at = self.opens_at;
+        let mut closes_at = self.closes_at;
+
+        if let Some(relative) = &self.relative {
+            match first_finished.get(&relative.after) {
+                Some(anchor) => {
+                    let unlocks_at = *anchor + Duration::days(i64::from(relative.delay_days));
+                    opens_at = opens_at.max(Some(unlocks_at));
+                    if let Some(days) = relative.open_for_days {
+                        let relative_close = unlocks_at + Duration::days(i64::from(days));
+                        closes_at = Some(closes_at.map_or(relative_close, |close| close.min(relative_close)));
+                    }
+                }
+                None => {
+                    return match closes_at {
+                        Some(closed_at) if now >= closed_at => WindowState::Closed { closed_at },
+                        _ => WindowState::NotYetOpen { unlocks_at: None },
+                    };
+                }
+            }
+        }
+
+        match (opens_at, closes_at) {
+            // Also covers a relative window that would only open after the
+            // absolute one has closed.
+            (Some(open), Some(closed_at)) if open >= closed_at => WindowState::Closed { closed_at },
+            (_, Some(closed_at)) if now >= closed_at => WindowState::Closed { closed_at },
+            (Some(open), _) if now < open => WindowState::NotYetOpen { unlocks_at: Some(open) },
+            _ => WindowState::Open { closes_at },
+        }
+    }
+
+    /// Why the phase can't be started under this window, if it can't.
+    pub fn reason(&self, state: WindowState) -> Option<String> {
+        match state {
+            WindowState::Open { .. } => None,
+            WindowState::NotYetOpen { unlocks_at: Some(unlocks_at) } => {
+                Some(format!("opens at {}", unlocks_at.format("%Y-%m-%d %H:%M UTC")))
+            }
+            WindowState::NotYetOpen { unlocks_at: None } => Some(match &self.relative {
+                Some(relative) => format!("opens {} day(s) after finishing {}", relative.delay_days, relative.after),
+                None => "not open yet".to_string(),
+            }),
+            WindowState::Closed { closed_at } => Some(format!("closed at {}", closed_at.format("%Y-%m-%d %H:%M UTC"))),
+        }
+    }
+}
+
+impl Phase {
+    /// Prerequisites the user has not satisfied yet.
+    pub fn unmet_prerequisites<'a>(&'a self, counts: &GameCounts) -> Vec<&'a Prerequisite> {
+        self.prerequisites
+            .iter()
+            .filter(|prerequisite| counts.get(prerequisite.game_type) < prerequisite.min_games)
+            .collect()
+    }
+
+    pub fn limit_reached(&self, counts: &GameCounts) -> bool {
+        self.max_attempts.is_some_and(|max| counts.get(self.game_type) >= max)
+    }
+
+    /// A user gets at most one game of a single-attempt phase, so a repeated
+    /// request is treated as resuming that game rather than a new attempt.
+    pub fn is_single_attempt(&self) -> bool {
+        self.max_attempts == Some(1)
+    }
+}
+
+/// The active study design, read from the `study_protocols` tables. Loaded
+/// fresh for each request that needs it, so a coordinator's edits apply
+/// immediately without a restart.
+#[derive(Debug, Clone)]
+pub struct Protocol {
+    pub id: i32,
+    pub name: String,
+    /// In protocol order.
+    pub phases: Vec<Phase>,
+    /// Parallel test forms the form phases rotate through, by position.
+    pub forms: Vec<TestForm>,
+}
+
+impl Protocol {
+    pub fn load_active(connection: &mut PgConnection) -> Result<Protocol, ApiError> {
+        let protocol = study_protocols::table
+            .filter(study_protocols::active.eq(true))
+            .select(StudyProtocol::as_select())
+            .first(connection)
+            .optional()?
+            .ok_or_else(|| ApiError::internal("No active study protocol"))?;
+
+        let phase_rows = study_phases::table
+            .filter(study_phases::protocol_id.eq(protocol.id))
+            .order(study_phases::position.asc())
+            .select(StudyPhase::as_select())
+            .load(connection)?;
+
+        let phase_ids: Vec<i32> = phase_rows.iter().map(|phase| phase.id).collect();
+        let mut prerequisites: HashMap<i32, Vec<Prerequisite>> = HashMap::new();
+        for row in study_phase_prerequisites::table
+            .filter(study_phase_prerequisites::phase_id.eq_any(&phase_ids))
+            .select(StudyPhasePrerequisite::as_select())
+            .load(connection)?
+        {
+            prerequisites.entry(row.phase_id).or_default().push(Prerequisite {
+                game_type: row.required_game_type,
+                min_games: i64::from(row.min_games),
+            });
+        }
+
+        let phases = phase_rows
+            .into_iter()
+            .map(|row| {
+                let draw = DrawMethod::parse(&row.draw).ok_or_else(|| {
+                    ApiError::internal(format!("Phase {} uses unknown draw method '{}'", row.game_type, row.draw))
+                })?;
+                let sampling = Sampling::parse(&row.sampling, row.sampling_counts.as_deref()).ok_or_else(|| {
+                    ApiError::internal(format!("Phase {} has invalid sampling '{}'", row.game_type, row.sampling))
+                })?;
+                if let Sampling::Explicit(counts) = sampling {
+                    if counts.iter().sum::<i32>() != row.challenges_per_game {
+                        return Err(ApiError::internal(format!(
+                            "Phase {} samples {:?} cores by score but has {} challenges per game",
+                            row.game_type, counts, row.challenges_per_game
+                        )));
+                    }
+                }
+                // A fixed seed promises every participant the same cores,
+                // which a rotation through their own history can't keep.
+                if row.fixed_seed.is_some() && row.no_repeat {
+                    return Err(ApiError::internal(format!(
+                        "Phase {} has a fixed seed, so it can't also rotate without repeats",
+                        row.game_type
+                    )));
+                }
+                // The table's CHECK constraint keeps unlocks_after and
+                // unlocks_after_days set together.
+                let relative = row.unlocks_after.zip(row.unlocks_after_days).map(|(after, delay_days)| RelativeWindow {
+                    after,
+                    delay_days,
+                    open_for_days: row.open_for_days,
+                });
+                Ok(Phase {
+                    prerequisites: prerequisites.remove(&row.id).unwrap_or_default(),
+                    id: row.id,
+                    game_type: row.game_type,
+                    position: row.position,
+                    max_attempts: row.max_attempts.map(i64::from),
+                    challenges_per_game: row.challenges_per_game,
+                    core_set: row.core_set,
+                    draw,
+                    sampling,
+                    fixed_seed: row.fixed_seed,
+                    uses_test_form: row.uses_test_form,
+                    rotation: row.no_repeat.then_some(Rotation { reshow_missed_rate: row.reshow_missed_rate }),
+                    resume_unfinished: row.resume_unfinished,
+                    window: PhaseWindow { opens_at: row.opens_at, closes_at: row.closes_at, relative },
+                    time_limit_secs: row.time_limit_secs,
+                })
+            })
+            .collect::<Result<Vec<_>, ApiError>>()?;
+
+        let forms = test_forms::table
+            .filter(test_forms::protocol_id.eq(protocol.id))
+            .order(test_forms::position.asc())
+            .select(TestForm::as_select())
+            .load::<TestForm>(connection)?;
+        // Each form phase needs its own form, or a participant would see the
+        // same cores twice.
+        let form_phases = phases.iter().filter(|phase| phase.uses_test_form).count();
+        if form_phases > forms.len() {
+            return Err(ApiError::internal(format!(
+                "Protocol {} has {} phase(s) using test forms but only {} form(s)",
+                protocol.name, form_phases, forms.len()
+            )));
+        }
+
+        Ok(Protocol { id: protocol.id, name: protocol.name, phases, forms })
+    }
+
+    pub fn phase(&self, game_type: GameMode) -> Option<&Phase> {
+        self.phases.iter().find(|phase| phase.game_type == game_type)
+    }
+}
+
+/// How many games of each type a user has created.
+#[derive(Debug, Clone, Default)]
+pub struct GameCounts(HashMap<GameMode, i64>);
+
+impl GameCounts {
+    pub fn load(connection: &mut PgConnection, user_id: &str) -> Result<GameCounts, ApiError> {
+        let counts = games::table
+            .filter(games::user_id.eq(user_id))
+            .group_by(games::game_type)
+            .select((games::game_type, count_star()))
+            .load::<(GameMode, i64)>(connection)?;
+        Ok(GameCounts(counts.into_iter().collect()))
+    }
+
+    pub fn get(&self, game_type: GameMode) -> i64 {
+        self.0.get(&game_type).copied().unwrap_or(0)
+    }
+}
+
+/// Namespace for the advisory locks below, so they can't collide with any
+/// other use of `pg_advisory_xact_lock` on the same database.
+const USER_GAMES_LOCK: i32 = 0x6761_6d65; // "game"
+
+/// Serializes game creation for one user until the end of the current
+/// transaction, so the counts read after taking it can't change before the
+/// caller inserts. Must be called inside a transaction.
+pub fn lock_user_games(connection: &mut PgConnection, user_id: &str) -> Result<(), ApiError> {
+    sql_query("SELECT pg_advisory_xact_lock($1, hashtext($2))")
+        .bind::<Integer, _>(USER_GAMES_LOCK)
+        .bind::<Text, _>(user_id)
+        .execute(connection)?;
+    Ok(())
+}
+
+/// A user with more games of one type than the active protocol allows, from
+/// before creation was serialized (see the `games_over_attempt_limit` view).
+#[derive(Debug, QueryableByName)]
+pub struct OverAttemptLimit {
+    #[diesel(sql_type = Varchar)]
+    pub user_id: String,
+    #[diesel(sql_type = Varchar)]
+    pub game_type: String,
+    #[diesel(sql_type = BigInt)]
+    pub games: i64,
+    #[diesel(sql_type = Integer)]
+    pub max_attempts: i32,
+    #[diesel(sql_type = Array<Integer>)]
+    pub game_ids: Vec<i32>,
+}
+
+pub fn games_over_attempt_limit(connection: &mut PgConnection) -> Result<Vec<OverAttemptLimit>, ApiError> {
+    Ok(sql_query("SELECT user_id, game_type, games, max_attempts, game_ids FROM games_over_attempt_limit")
+        .load(connection)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::TimeZone;
+
+    fn at(day: u32, hour: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 3, day, hour, 0, 0).unwrap()
+    }
+
+    fn second() -> Duration {
+        Duration::seconds(1)
+    }
+
+    fn posttest_window(open_for_days: Option<i32>) -> PhaseWindow {
+        PhaseWindow {
+            relative: Some(RelativeWindow { after: GameMode::Pretest, delay_days: 14, open_for_days }),
+            ..PhaseWindow::default()
+        }
+    }
+
+    #[test]
+    fn unbounded_window_is_open() {
+        let state = PhaseWindow::default().state(&HashMap::new(), at(1, 0));
+        assert_eq!(state, WindowState::Open { closes_at: None });
+    }
+
+    #[test]
+    fn absolute_window_boundaries() {
+        let window = PhaseWindow { opens_at: Some(at(2, 9)), closes_at: Some(at(9, 17)), relative: None };
+        let state = |now| window.state(&HashMap::new(), now);
+
+        assert_eq!(state(at(2, 9) - second()), WindowState::NotYetOpen { unlocks_at: Some(at(2, 9)) });
+        assert_eq!(state(at(2, 9)), WindowState::Open { closes_at: Some(at(9, 17)) });
+        assert_eq!(state(at(9, 17) - second()), WindowState::Open { closes_at: Some(at(9, 17)) });
+        assert_eq!(state(at(9, 17)), WindowState::Closed { closed_at: at(9, 17) });
+    }
+
+    #[test]
+    fn posttest_unlocks_14_days_after_pretest() {
+        let window = posttest_window(Some(7));
+        let finished = HashMap::from([(GameMode::Pretest, at(1, 12))]);
+        let unlocks_at = at(15, 12);
+        let closes_at = at(22, 12);
+        let state = |now| window.state(&finished, now);
+
+        assert_eq!(state(unlocks_at - second()), WindowState::NotYetOpen { unlocks_at: Some(unlocks_at) });
+        assert_eq!(state(unlocks_at), WindowState::Open { closes_at: Some(closes_at) });
+        assert_eq!(state(closes_at - second()), WindowState::Open { closes_at: Some(closes_at) });
+        assert_eq!(state(closes_at), WindowState::Closed { closed_at: closes_at });
+    }
+
+    #[test]
+    fn relative_window_waits_for_its_anchor() {
+        let window = posttest_window(None);
+        assert_eq!(window.state(&HashMap::new(), at(30, 0)), WindowState::NotYetOpen { unlocks_at: None });
+
+        let finished = HashMap::from([(GameMode::Pretest, at(1, 12))]);
+        assert_eq!(window.state(&finished, at(30, 0)), WindowState::Open { closes_at: None });
+    }
+
+    #[test]
+    fn absolute_close_ends_a_pending_relative_window() {
+        let window = PhaseWindow { closes_at: Some(at(10, 0)), ..posttest_window(None) };
+        assert_eq!(window.state(&HashMap::new(), at(10, 0) - second()), WindowState::NotYetOpen { unlocks_at: None });
+        assert_eq!(window.state(&HashMap::new(), at(10, 0)), WindowState::Closed { closed_at: at(10, 0) });
+
+        // Unlocking after the study closes means it never opens.
+        let finished = HashMap::from([(GameMode::Pretest, at(1, 12))]);
+        assert_eq!(window.state(&finished, at(2, 0)), WindowState::Closed { closed_at: at(10, 0) });
+    }
+
+    #[test]
+    fn both_windows_must_be_open() {
+        let window = PhaseWindow { opens_at: Some(at(20, 0)), closes_at: Some(at(25, 0)), ..posttest_window(Some(7)) };
+        let finished = HashMap::from([(GameMode::Pretest, at(1, 12))]);
+        let state = |now| window.state(&finished, now);
+
+        // Relative unlock on the 15th, but the absolute window opens later.
+        assert_eq!(state(at(19, 0)), WindowState::NotYetOpen { unlocks_at: Some(at(20, 0)) });
+        // Relative close on the 22nd comes before the absolute one.
+        assert_eq!(state(at(20, 0)), WindowState::Open { closes_at: Some(at(22, 12)) });
+        assert_eq!(state(at(22, 12)), WindowState::Closed { closed_at: at(22, 12) });
+    }
+}
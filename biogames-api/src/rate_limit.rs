@@ -0,0 +1,358 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{ConnectInfo, MatchedPath, Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::{IntoResponse, Response}
+};
+
+use crate::{
+    config::{RateLimitConfig, RatePolicy},
+    error::ApiError
+};
+
+/// Outcome of taking one token from a bucket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Allowed,
+    Limited { retry_after: Duration },
+}
+
+/// Where bucket state lives. The in-memory backend is enough for a single
+/// API process; a shared store (e.g. Redis) can implement this to limit
+/// across several.
+pub trait RateLimitBackend: Send + Sync {
+    /// Takes one token from the bucket `key` under `policy`.
+    fn take(&self, key: &str, policy: &RatePolicy) -> Decision;
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// When the bucket will be back at `burst`, i.e. indistinguishable from
+    /// one that was never used.
+    full_at: Instant,
+}
+
+/// Token buckets in a process-local map. Buckets that have refilled
+/// completely carry no information, so they are dropped periodically.
+pub struct InMemoryBackend {
+    buckets: Mutex<HashMap<String, Bucket>>,
+    last_prune: Mutex<Instant>,
+}
+
+impl InMemoryBackend {
+    const PRUNE_EVERY: Duration = Duration::from_secs(60);
+
+    pub fn new() -> Self {
+        InMemoryBackend {
+            buckets: Mutex::new(HashMap::new()),
+            last_prune: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn prune(&self, buckets: &mut HashMap<String, Bucket>, now: Instant) {
+        let mut last_prune = self.last_prune.lock().unwrap();
+        if now.duration_since(*last_prune) < Self::PRUNE_EVERY {
+            return;
+        }
+        *last_prune = now;
+        buckets.retain(|_, bucket| bucket.full_at > now);
+    }
+}
+
+impl Default for InMemoryBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RateLimitBackend for InMemoryBackend {
+    fn take(&self, key: &str, policy: &RatePolicy) -> Decision {
+        self.take_at(key, policy, Instant::now())
+    }
+}
+
+impl InMemoryBackend {
+    fn take_at(&self, key: &str, policy: &RatePolicy, now: Instant) -> Decision {
+        let burst = f64::from(policy.burst);
+        let per_second = policy.per_minute / 60.0;
+
+        let mut buckets = self.buckets.lock().unwrap();
+        self.prune(&mut buckets, now);
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket { tokens: burst, updated: now, full_at: now });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_second).min(burst);
+        bucket.updated = now;
+
+        let decision = if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Decision::Allowed
+        } else {
+            Decision::Limited { retry_after: Duration::from_secs_f64((1.0 - bucket.tokens) / per_second) }
+        };
+        bucket.full_at = now + Duration::from_secs_f64((burst - bucket.tokens) / per_second);
+        decision
+    }
+}
+
+/// Which limit an IP-keyed request counts against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteClass {
+    Default,
+    Lookup,
+    Registration,
+}
+
+impl RouteClass {
+    fn for_route(route: &str) -> RouteClass {
+        match route {
+            "/generate-user-id" | "/register-with-username" => RouteClass::Registration,
+            "/validate-username/:username"
+            | "/check-username/:user_id"
+            | "/check-game-type/:user_id"
+            | "/users/:user_id/active-game"
+            | "/users/:user_id/progress" => RouteClass::Lookup,
+            _ => RouteClass::Default,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            RouteClass::Default => "default",
+            RouteClass::Lookup => "lookup",
+            RouteClass::Registration => "registration",
+        }
+    }
+}
+
+/// Shared by the IP middleware and the handlers that know a user id.
+#[derive(Clone)]
+pub struct RateLimiter {
+    config: Arc<RateLimitConfig>,
+    backend: Arc<dyn RateLimitBackend>,
+    /// Set once the untrusted proxy warning has been logged.
+    warned_untrusted_proxy: Arc<AtomicBool>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig, backend: Arc<dyn RateLimitBackend>) -> Self {
+        RateLimiter { config: Arc::new(config), backend, warned_untrusted_proxy: Arc::new(AtomicBool::new(false)) }
+    }
+
+    pub fn in_memory(config: RateLimitConfig) -> Self {
+        Self::new(config, Arc::new(InMemoryBackend::new()))
+    }
+
+    fn policy(&self, class: RouteClass) -> &RatePolicy {
+        match class {
+            RouteClass::Default => &self.config.default,
+            RouteClass::Lookup => &self.config.lookup,
+            RouteClass::Registration => &self.config.registration,
+        }
+    }
+
+    fn take(&self, scope: &'static str, key: &str, policy: &RatePolicy) -> Result<(), ApiError> {
+        if !self.config.enabled {
+            return Ok(());
+        }
+        match self.backend.take(&format!("{}:{}", scope, key), policy) {
+            Decision::Allowed => Ok(()),
+            Decision::Limited { retry_after } => {
+                tracing::warn!(scope, key, "Rate limited, retry after {:?}", retry_after);
+                metrics::counter!("biogames_rate_limited_total", "scope" => scope).increment(1);
+                Err(ApiError::RateLimited { retry_after })
+            }
+        }
+    }
+
+    /// Counts a request from `ip` against the limit for its route class.
+    pub fn check_ip(&self, class: RouteClass, ip: IpAddr) -> Result<(), ApiError> {
+        self.take(class.as_str(), &ip.to_string(), self.policy(class))
+    }
+
+    /// Counts a request made on behalf of `user_id`, wherever it comes from.
+    pub fn check_user(&self, user_id: &str) -> Result<(), ApiError> {
+        self.take("per_user", user_id, &self.config.per_user)
+    }
+
+    /// The address to key on: the peer itself, or, when the peer is a trusted
+    /// proxy, the nearest untrusted hop in `X-Forwarded-For`.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        // A dual-stack listener reports IPv4 peers as `::ffff:a.b.c.d`.
+        let peer = peer.to_canonical();
+        if !self.config.trusted_proxies.contains(&peer) {
+            if headers.contains_key("x-forwarded-for") && !self.warned_untrusted_proxy.swap(true, Ordering::Relaxed) {
+                tracing::warn!(
+                    %peer,
+                    "Request forwarded by an untrusted proxy; everyone behind it shares one rate limit. \
+                     Add it to rate_limit.trusted_proxies if it is ours"
+                );
+            }
+            return peer;
+        }
+        headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|hop| hop.trim().parse::<IpAddr>().ok())
+            .rev()
+            .find(|ip| !self.config.trusted_proxies.contains(ip))
+            .unwrap_or(peer)
+    }
+}
+
+/// Applies the per-IP limits. Mounted only on the public routes; operator
+/// routes (health, metrics, analytics) are never limited.
+pub async fn limit_by_ip(
+    State(limiter): State<RateLimiter>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next
+) -> Response {
+    let class = request.extensions()
+        .get::<MatchedPath>()
+        .map(|path| RouteClass::for_route(path.as_str()))
+        .unwrap_or(RouteClass::Default);
+    let ip = limiter.client_ip(peer.ip(), request.headers());
+
+    match limiter.check_ip(class, ip) {
+        Ok(()) => next.run(request).await,
+        Err(e) => e.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::http::{header::RETRY_AFTER, HeaderValue};
+
+    const POLICY: RatePolicy = RatePolicy { burst: 3, per_minute: 6.0 };
+
+    #[test]
+    fn bucket_allows_burst_then_limits() {
+        let backend = InMemoryBackend::new();
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(backend.take_at("k", &POLICY, now), Decision::Allowed);
+        }
+        // 6 per minute is one token every 10 seconds.
+        assert_eq!(
+            backend.take_at("k", &POLICY, now),
+            Decision::Limited { retry_after: Duration::from_secs(10) }
+        );
+        // Other keys have their own bucket.
+        assert_eq!(backend.take_at("other", &POLICY, now), Decision::Allowed);
+    }
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let backend = InMemoryBackend::new();
+        let now = Instant::now();
+        for _ in 0..3 {
+            backend.take_at("k", &POLICY, now);
+        }
+        let Decision::Limited { retry_after } = backend.take_at("k", &POLICY, now + Duration::from_secs(4)) else {
+            panic!("expected the bucket to still be empty");
+        };
+        assert!((retry_after.as_secs_f64() - 6.0).abs() < 1e-6);
+
+        assert_eq!(backend.take_at("k", &POLICY, now + Duration::from_secs(10)), Decision::Allowed);
+        assert!(matches!(backend.take_at("k", &POLICY, now + Duration::from_secs(10)), Decision::Limited { .. }));
+
+        // Refilling stops at the burst size.
+        let later = now + Duration::from_secs(3600);
+        for _ in 0..3 {
+            assert_eq!(backend.take_at("k", &POLICY, later), Decision::Allowed);
+        }
+        assert!(matches!(backend.take_at("k", &POLICY, later), Decision::Limited { .. }));
+    }
+
+    #[test]
+    fn full_buckets_are_pruned() {
+        let backend = InMemoryBackend::new();
+        let now = Instant::now();
+        backend.take_at("idle", &POLICY, now);
+        for _ in 0..3 {
+            backend.take_at("busy", &POLICY, now + Duration::from_secs(55));
+        }
+
+        // "idle" is full again 10 seconds after its one request; "busy"
+        // needs 30 seconds from its last.
+        backend.take_at("new", &POLICY, now + InMemoryBackend::PRUNE_EVERY + Duration::from_secs(1));
+        let buckets = backend.buckets.lock().unwrap();
+        assert!(!buckets.contains_key("idle"));
+        assert!(buckets.contains_key("busy"));
+        assert!(buckets.contains_key("new"));
+    }
+
+    #[test]
+    fn retry_after_header_rounds_up_to_whole_seconds() {
+        let response = ApiError::RateLimited { retry_after: Duration::from_millis(1500) }.into_response();
+        assert_eq!(response.status(), axum::http::StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers().get(RETRY_AFTER), Some(&HeaderValue::from(2u64)));
+
+        let response = ApiError::RateLimited { retry_after: Duration::ZERO }.into_response();
+        assert_eq!(response.headers().get(RETRY_AFTER), Some(&HeaderValue::from(1u64)));
+    }
+
+    fn limiter(trusted: &[&str]) -> RateLimiter {
+        RateLimiter::in_memory(RateLimitConfig {
+            trusted_proxies: trusted.iter().map(|ip| ip.parse().unwrap()).collect(),
+            ..RateLimitConfig::default()
+        })
+    }
+
+    fn forwarded(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn untrusted_peer_is_the_client() {
+        let limiter = limiter(&["10.0.0.1"]);
+        assert_eq!(limiter.client_ip(ip("203.0.113.7"), &forwarded("198.51.100.1")), ip("203.0.113.7"));
+        assert_eq!(limiter.client_ip(ip("203.0.113.7"), &HeaderMap::new()), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn trusted_peer_uses_nearest_untrusted_hop() {
+        let limiter = limiter(&["10.0.0.1", "10.0.0.2"]);
+        // A client can prepend anything; only the hops our proxies added count.
+        let headers = forwarded("1.2.3.4, 198.51.100.9, 10.0.0.2");
+        assert_eq!(limiter.client_ip(ip("10.0.0.1"), &headers), ip("198.51.100.9"));
+
+        let mut headers = forwarded("1.2.3.4");
+        headers.append("x-forwarded-for", HeaderValue::from_static("198.51.100.9, garbage"));
+        assert_eq!(limiter.client_ip(ip("10.0.0.1"), &headers), ip("198.51.100.9"));
+    }
+
+    #[test]
+    fn trusted_peer_without_forwarding_is_the_client() {
+        let limiter = limiter(&["10.0.0.1"]);
+        assert_eq!(limiter.client_ip(ip("10.0.0.1"), &HeaderMap::new()), ip("10.0.0.1"));
+        assert_eq!(limiter.client_ip(ip("10.0.0.1"), &forwarded("10.0.0.1")), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn loopback_is_trusted_by_default_even_when_ipv4_mapped() {
+        let limiter = RateLimiter::in_memory(RateLimitConfig::default());
+        let headers = forwarded("198.51.100.9");
+        assert_eq!(limiter.client_ip(ip("127.0.0.1"), &headers), ip("198.51.100.9"));
+        assert_eq!(limiter.client_ip(ip("::1"), &headers), ip("198.51.100.9"));
+        assert_eq!(limiter.client_ip(ip("::ffff:127.0.0.1"), &headers), ip("198.51.100.9"));
+    }
+}
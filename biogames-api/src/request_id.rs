@@ -0,0 +1,43 @@
+use axum::{
+    extract::Request,
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response
+};
+use uuid::Uuid;
+
+pub static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// Assigns every request an id, reusing the caller's `X-Request-Id` when it is
+/// a sane value, and echoes it back on the response. The id is kept in a task
+/// local for the duration of the request so error bodies can include it.
+pub async fn request_id(mut req: Request, next: Next) -> Response {
+    let id = req.headers()
+        .get(&X_REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| is_acceptable_id(v))
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::now_v7().to_string());
+
+    let header_value = HeaderValue::from_str(&id).expect("request ids are visible ASCII");
+    req.headers_mut().insert(X_REQUEST_ID.clone(), header_value.clone());
+
+    let mut response = REQUEST_ID.scope(id, next.run(req)).await;
+    response.headers_mut().insert(X_REQUEST_ID.clone(), header_value);
+    response
+}
+
+/// The id of the request currently being handled, if called from within one.
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+fn is_acceptable_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 128
+        && id.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':'))
+}
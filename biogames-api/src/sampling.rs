@@ -0,0 +1,196 @@
+/// HER2 scores run from 0 to 3.
+pub const SCORE_CLASSES: usize = 4;
+
+/// How a random draw is spread over the HER2 score classes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sampling {
+    /// Ignore the classes; every core in the set is equally likely.
+    Uniform,
+    /// The same number of cores of each score.
+    Balanced,
+    /// Each score in proportion to its share of the core set.
+    Proportional,
+    /// Fixed numbers of cores, indexed by score.
+    Explicit([i32; SCORE_CLASSES]),
+}
+
+/// Cores of each score to draw, indexed by score.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Allocation {
+    pub counts: [i32; SCORE_CLASSES],
+    /// Some class had fewer cores than the strategy asked for, and the gap
+    /// was filled from the others.
+    pub topped_up: bool,
+}
+
+impl Sampling {
+    /// `counts` is `study_phases.sampling_counts`, only used for `explicit`.
+    pub fn parse(name: &str, counts: Option<&[i32]>) -> Option<Sampling> {
+        match name {
+            "uniform" => Some(Sampling::Uniform),
+            "balanced" => Some(Sampling::Balanced),
+            "proportional" => Some(Sampling::Proportional),
+            "explicit" => counts?.try_into().ok().map(Sampling::Explicit),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Sampling::Uniform => "uniform",
+            Sampling::Balanced => "balanced",
+            Sampling::Proportional => "proportional",
+            Sampling::Explicit(_) => "explicit",
+        }
+    }
+
+    /// Splits `total` cores over the classes given how many of each are
+    /// `available`. `None` for `Uniform`, which doesn't look at classes. The
+    /// counts add up to `total` unless the set has fewer cores than that
+    /// altogether.
+    pub fn allocate(&self, total: i32, available: [i64; SCORE_CLASSES]) -> Option<Allocation> {
+        let wanted = match self {
+            Sampling::Uniform => return None,
+            Sampling::Balanced => largest_remainder(total, [1.0; SCORE_CLASSES]),
+            Sampling::Proportional => largest_remainder(total, available.map(|count| count as f64)),
+            Sampling::Explicit(counts) => *counts,
+        };
+
+        let mut counts = [0; SCORE_CLASSES];
+        for class in 0..SCORE_CLASSES {
+            counts[class] = i64::from(wanted[class]).min(available[class]) as i32;
+        }
+        let topped_up = counts != wanted;
+
+        // Each missing core goes to the class with the fewest so far that
+        // still has some to spare, keeping the game as even as it can be.
+        let mut missing = total - counts.iter().sum::<i32>();
+        while missing > 0 {
+            let spare = (0..SCORE_CLASSES)
+                .filter(|&class| i64::from(counts[class]) < available[class])
+                .min_by_key(|&class| counts[class]);
+            match spare {
+                Some(class) => counts[class] += 1,
+                None => break,
+            }
+            missing -= 1;
+        }
+
+        Some(Allocation { counts, topped_up })
+    }
+}
+
+/// Splits `total` in proportion to `weights` with the largest remainder
+/// method. Ties go to the lower score.
+fn largest_remainder(total: i32, weights: [f64; SCORE_CLASSES]) -> [i32; SCORE_CLASSES] {
+    let sum: f64 = weights.iter().sum();
+    if sum <= 0.0 {
+        return [0; SCORE_CLASSES];
+    }
+    let quotas = weights.map(|weight| f64::from(total) * weight / sum);
+    let mut counts = quotas.map(|quota| quota.floor() as i32);
+
+    let mut by_remainder: Vec<usize> = (0..SCORE_CLASSES).collect();
+    by_remainder.sort_by(|&a, &b| (quotas[b] - quotas[b].floor()).total_cmp(&(quotas[a] - quotas[a].floor())));
+    let leftover = (total - counts.iter().sum::<i32>()).max(0) as usize;
+    for &class in by_remainder.iter().take(leftover) {
+        counts[class] += 1;
+    }
+    counts
+}
+
+/// Cores of each score still to draw for a game of `total` challenges.
+/// `available` counts the set's cores besides the initial core, whose class
+/// is `initial_class` if the game has one. `None` means draw uniformly.
+pub fn class_targets(
+    sampling: Sampling,
+    total: i32,
+    mut available: [i64; SCORE_CLASSES],
+    initial_class: Option<usize>
+) -> Option<Allocation> {
+    if let Some(class) = initial_class {
+        available[class] += 1;
+    }
+    let mut allocation = sampling.allocate(total, available)?;
+
+    // The initial core already fills a slot of its own class, or of the
+    // largest class if the strategy wanted none of its score.
+    if let Some(class) = initial_class {
+        let counts = &mut allocation.counts;
+        let class = if counts[class] > 0 {
+            class
+        } else {
+            (0..SCORE_CLASSES).max_by_key(|&class| counts[class]).unwrap_or(class)
+        };
+        counts[class] = (counts[class] - 1).max(0);
+    }
+    Some(allocation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allocation(counts: [i32; SCORE_CLASSES], topped_up: bool) -> Option<Allocation> {
+        Some(Allocation { counts, topped_up })
+    }
+
+    #[test]
+    fn uniform_ignores_classes() {
+        assert_eq!(Sampling::Uniform.allocate(10, [10; SCORE_CLASSES]), None);
+        assert_eq!(class_targets(Sampling::Uniform, 10, [10; SCORE_CLASSES], Some(2)), None);
+    }
+
+    #[test]
+    fn balanced_remainder_goes_to_lower_scores() {
+        assert_eq!(Sampling::Balanced.allocate(8, [10; SCORE_CLASSES]), allocation([2, 2, 2, 2], false));
+        assert_eq!(Sampling::Balanced.allocate(10, [10; SCORE_CLASSES]), allocation([3, 3, 2, 2], false));
+        assert_eq!(Sampling::Balanced.allocate(7, [10; SCORE_CLASSES]), allocation([2, 2, 2, 1], false));
+    }
+
+    #[test]
+    fn proportional_follows_the_set() {
+        assert_eq!(Sampling::Proportional.allocate(10, [10, 20, 30, 40]), allocation([1, 2, 3, 4], false));
+        // Quotas 1.75, 1.75, 3.5, 0: the two largest remainders tie and both
+        // round up.
+        assert_eq!(Sampling::Proportional.allocate(7, [5, 5, 10, 0]), allocation([2, 2, 3, 0], false));
+    }
+
+    #[test]
+    fn short_class_is_topped_up_from_the_smallest_others() {
+        assert_eq!(Sampling::Explicit([2, 2, 2, 2]).allocate(8, [0, 5, 5, 5]), allocation([0, 3, 3, 2], true));
+        assert_eq!(Sampling::Balanced.allocate(8, [1, 10, 10, 10]), allocation([1, 3, 2, 2], true));
+    }
+
+    #[test]
+    fn small_set_gives_what_it_has() {
+        assert_eq!(Sampling::Balanced.allocate(8, [1, 1, 0, 0]), allocation([1, 1, 0, 0], true));
+    }
+
+    #[test]
+    fn initial_core_fills_a_slot_of_its_class() {
+        assert_eq!(
+            class_targets(Sampling::Balanced, 8, [5; SCORE_CLASSES], Some(1)),
+            allocation([2, 1, 2, 2], false)
+        );
+    }
+
+    #[test]
+    fn initial_core_of_an_unwanted_class_takes_from_the_largest() {
+        // The initial core scored 0 but the strategy wants none of those.
+        assert_eq!(
+            class_targets(Sampling::Explicit([0, 2, 4, 2]), 8, [0, 5, 5, 5], Some(0)),
+            allocation([0, 2, 3, 2], false)
+        );
+    }
+
+    #[test]
+    fn parse_round_trips() {
+        for sampling in [Sampling::Uniform, Sampling::Balanced, Sampling::Proportional, Sampling::Explicit([1, 2, 3, 4])] {
+            assert_eq!(Sampling::parse(sampling.as_str(), Some(&[1, 2, 3, 4])), Some(sampling));
+        }
+        assert_eq!(Sampling::parse("explicit", None), None);
+        assert_eq!(Sampling::parse("explicit", Some(&[1, 2, 3])), None);
+        assert_eq!(Sampling::parse("stratified", None), None);
+    }
+}
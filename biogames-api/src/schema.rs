@@ -9,6 +9,25 @@ diesel::table! {
         started_at -> Nullable<Timestamptz>,
         submitted_at -> Nullable<Timestamptz>,
         points -> Nullable<Int4>,
+        timed_out -> Bool,
+    }
+}
+
+diesel::table! {
+    core_set_members (core_set_id, core_id) {
+        core_set_id -> Int4,
+        core_id -> Int4,
+    }
+}
+
+diesel::table! {
+    core_sets (id) {
+        id -> Int4,
+        name -> Text,
+        description -> Nullable<Text>,
+        frozen -> Bool,
+        created_at -> Timestamptz,
+        frozen_at -> Nullable<Timestamptz>,
     }
 }
 
@@ -20,6 +39,16 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    form_assignments (user_id, phase_id) {
+        user_id -> Text,
+        phase_id -> Int4,
+        test_form_id -> Int4,
+        rotation -> Int4,
+        assigned_at -> Timestamptz,
+    }
+}
+
 diesel::table! {
     games (id) {
         id -> Int4,
@@ -33,6 +62,16 @@ diesel::table! {
         game_type -> Varchar,
         #[max_length = 32]
         user_id -> Varchar,
+        end_reason -> Nullable<Varchar>,
+        time_limit_secs -> Nullable<Int4>,
+        core_set -> Nullable<Text>,
+        sampling -> Nullable<Varchar>,
+        seed -> Nullable<Int8>,
+        initial_core_id -> Nullable<Int4>,
+        sampling_counts -> Nullable<Array<Int4>>,
+        test_form -> Nullable<Text>,
+        no_repeat -> Bool,
+        reshow_missed_rate -> Nullable<Float8>,
     }
 }
 
@@ -55,13 +94,83 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    study_phase_prerequisites (id) {
+        id -> Int4,
+        phase_id -> Int4,
+        required_game_type -> Varchar,
+        min_games -> Int4,
+    }
+}
+
+diesel::table! {
+    study_phases (id) {
+        id -> Int4,
+        protocol_id -> Int4,
+        game_type -> Varchar,
+        position -> Int4,
+        max_attempts -> Nullable<Int4>,
+        challenges_per_game -> Int4,
+        core_set -> Text,
+        resume_unfinished -> Bool,
+        opens_at -> Nullable<Timestamptz>,
+        closes_at -> Nullable<Timestamptz>,
+        unlocks_after -> Nullable<Varchar>,
+        unlocks_after_days -> Nullable<Int4>,
+        open_for_days -> Nullable<Int4>,
+        time_limit_secs -> Nullable<Int4>,
+        draw -> Varchar,
+        sampling -> Varchar,
+        sampling_counts -> Nullable<Array<Int4>>,
+        fixed_seed -> Nullable<Int8>,
+        uses_test_form -> Bool,
+        no_repeat -> Bool,
+        reshow_missed_rate -> Float8,
+    }
+}
+
+diesel::table! {
+    study_protocols (id) {
+        id -> Int4,
+        name -> Text,
+        description -> Nullable<Text>,
+        active -> Bool,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    test_forms (id) {
+        id -> Int4,
+        protocol_id -> Int4,
+        name -> Text,
+        position -> Int4,
+        core_set -> Text,
+    }
+}
+
 diesel::joinable!(challenges -> games (game_id));
 diesel::joinable!(challenges -> her2_cores (core_id));
+diesel::joinable!(core_set_members -> core_sets (core_set_id));
+diesel::joinable!(core_set_members -> her2_cores (core_id));
+diesel::joinable!(form_assignments -> study_phases (phase_id));
+diesel::joinable!(form_assignments -> test_forms (test_form_id));
+diesel::joinable!(games -> her2_cores (initial_core_id));
+diesel::joinable!(study_phase_prerequisites -> study_phases (phase_id));
+diesel::joinable!(study_phases -> study_protocols (protocol_id));
+diesel::joinable!(test_forms -> study_protocols (protocol_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
     challenges,
+    core_set_members,
+    core_sets,
     email_registry,
+    form_assignments,
     games,
     her2_cores,
     registered_users,
+    study_phase_prerequisites,
+    study_phases,
+    study_protocols,
+    test_forms,
 );
@@ -8,9 +8,18 @@ pub const HER2_CONFUSION_MATRIX: [[i32; 4]; 4] = [
     [-4, -2, -1,  5], // Guess 3
 ];
 
+/// Best possible points for one challenge, i.e. a correct guess.
+pub const MAX_POINTS_PER_CHALLENGE: i32 = 5;
+
+/// Points for a submission that arrived after the challenge's deadline,
+/// whatever the guess. `penalty` is `scoring.timeout_penalty`.
+pub fn get_timed_out_score(penalty: i32) -> i32 {
+    -penalty
+}
+
 /// Get score from confusion matrix for a guess and ground truth value
 pub fn get_score(guess: i32, ground_truth: i32) -> i32 {
-    if guess < 0 || guess > 3 || ground_truth < 0 || ground_truth > 3 {
+    if !(0..=3).contains(&guess) || !(0..=3).contains(&ground_truth) {
         return -5; // Default to highest penalty for out-of-range values
     }
     
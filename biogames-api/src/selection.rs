@@ -0,0 +1,436 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use diesel::{
+    prelude::*,
+    sql_query,
+    sql_types::{Double, Integer, Text, Timestamptz}
+};
+use rand::{seq::SliceRandom, Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use crate::{
+    error::ApiError,
+    protocol::{DrawMethod, Rotation},
+    sampling::{class_targets, Sampling, SCORE_CLASSES},
+    schema::{challenges, core_set_members, games, her2_cores}
+};
+
+/// The mistakes a review game can draw from as of its start, each weighted
+/// by how bad the mistakes were (the points lost) and how recent: the weight
+/// halves every `half_life_days` (bound as `$4`). Timeouts aren't mistakes
+/// in scoring.
+const SQL_MISTAKES: &str = r#"
+SELECT c.core_id,
+       SUM(-c.points * POWER(0.5, EXTRACT(epoch FROM $3 - c.submitted_at) / 86400.0 / $4))::float8 AS weight
+FROM challenges c
+JOIN games g ON g.id = c.game_id
+WHERE g.user_id = $1
+  AND c.points < 0
+  AND NOT c.timed_out
+  AND c.submitted_at IS NOT NULL
+  AND c.submitted_at < $3
+  AND c.core_id IN (SELECT core_id FROM core_set_members WHERE core_set_id = $2)
+GROUP BY c.core_id
+ORDER BY c.core_id
+"#;
+
+#[derive(QueryableByName)]
+struct Mistake {
+    #[diesel(sql_type = Integer)]
+    core_id: i32,
+    #[diesel(sql_type = Double)]
+    weight: f64,
+}
+
+/// Everything a game's draw depends on besides the seed. Given the same spec
+/// and seed, `select` returns the same cores in the same order, as long as
+/// the core set (unless frozen) and the player's earlier games haven't
+/// changed.
+#[derive(Debug, Clone)]
+pub struct DrawSpec<'a> {
+    pub draw: DrawMethod,
+    pub core_set_id: i32,
+    pub sampling: Sampling,
+    /// Challenges in the game, the initial core included.
+    pub total: i32,
+    /// Placed first by the caller and left out of the draw.
+    pub initial_core_id: Option<i32>,
+    pub user_id: &'a str,
+    /// The game being drawn for. Rotation looks at the player's games
+    /// before it.
+    pub game_id: i32,
+    pub rotation: Option<Rotation>,
+    /// Review weights are computed as of this moment (the game's start),
+    /// from mistakes submitted before it.
+    pub as_of: DateTime<Utc>,
+    pub half_life_days: f64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Selection {
+    /// In challenge order, without the initial core.
+    pub core_ids: Vec<i32>,
+    /// Stratified sampling had to fill a short class from the others.
+    pub topped_up: bool,
+    /// Previously mis-scored cores re-shown on purpose by the rotation.
+    pub reshown: usize,
+    /// Already served cores the rotation had to reuse because the set had
+    /// too few new ones.
+    pub recycled: usize,
+}
+
+/// A seed for a game that doesn't have a fixed one.
+pub fn new_seed() -> i64 {
+    rand::random()
+}
+
+/// The generator is ChaCha8, whose output is fixed for a given seed, but the
+/// shuffling and sampling on top of it come from `rand`; upgrading either
+/// crate's major version can change what a stored seed selects.
+fn rng(seed: i64) -> ChaCha8Rng {
+    ChaCha8Rng::seed_from_u64(seed as u64)
+}
+
+/// Draws the cores for a game. Candidates are read in core id order so the
+/// RNG sees them the same way every time.
+pub fn select(connection: &mut PgConnection, spec: &DrawSpec, seed: i64) -> Result<Selection, ApiError> {
+    let mut rng = rng(seed);
+    match spec.draw {
+        DrawMethod::Random => {
+            let candidates = random_candidates(connection, spec.core_set_id, spec.initial_core_id)?;
+            let initial_class = match spec.initial_core_id {
+                Some(core_id) => score_class(
+                    her2_cores::table.find(core_id).select(her2_cores::score).first::<i32>(connection)?
+                ),
+                None => None,
+            };
+            let history = match spec.rotation {
+                Some(rotation) => Some((rotation, served_cores(connection, spec.user_id, spec.game_id)?)),
+                None => None,
+            };
+            let remaining = (spec.total - i32::from(spec.initial_core_id.is_some())).max(0) as usize;
+            Ok(draw_random(
+                candidates,
+                spec.sampling,
+                spec.total,
+                remaining,
+                initial_class,
+                history.as_ref().map(|(rotation, served)| (*rotation, served)),
+                &mut rng
+            ))
+        }
+        DrawMethod::ReviewMistakes => {
+            let mistakes = sql_query(SQL_MISTAKES)
+                .bind::<Text, _>(spec.user_id)
+                .bind::<Integer, _>(spec.core_set_id)
+                .bind::<Timestamptz, _>(spec.as_of)
+                .bind::<Double, _>(spec.half_life_days)
+                .load::<Mistake>(connection)?;
+            Ok(Selection { core_ids: draw_weighted(mistakes, spec.total, &mut rng), ..Selection::default() })
+        }
+    }
+}
+
+/// The cores of a random draw that doesn't depend on the player: no preview
+/// core and no rotation, as in a fixed-seed phase. Gives the same cores as
+/// `select` would for such a game.
+pub fn fixed_order(
+    connection: &mut PgConnection,
+    core_set_id: i32,
+    sampling: Sampling,
+    total: i32,
+    seed: i64
+) -> Result<Selection, ApiError> {
+    let candidates = random_candidates(connection, core_set_id, None)?;
+    Ok(draw_random(candidates, sampling, total, total.max(0) as usize, None, None, &mut rng(seed)))
+}
+
+/// The first challenge of a `fixed_order` game, which is what its preview
+/// shows.
+pub fn fixed_first_core(
+    connection: &mut PgConnection,
+    core_set_id: i32,
+    sampling: Sampling,
+    total: i32,
+    seed: i64
+) -> Result<Option<i32>, ApiError> {
+    Ok(fixed_order(connection, core_set_id, sampling, total, seed)?.core_ids.first().copied())
+}
+
+/// The set's cores with their scores, besides the initial core.
+fn random_candidates(
+    connection: &mut PgConnection,
+    core_set_id: i32,
+    initial_core_id: Option<i32>
+) -> Result<Vec<(i32, i32)>, ApiError> {
+    let excluded: Vec<i32> = initial_core_id.into_iter().collect();
+    let candidates = core_set_members::table
+        .inner_join(her2_cores::table)
+        .filter(core_set_members::core_set_id.eq(core_set_id))
+        .filter(core_set_members::core_id.ne_all(&excluded))
+        .order(core_set_members::core_id.asc())
+        .select((core_set_members::core_id, her2_cores::score))
+        .load::<(i32, i32)>(connection)?;
+    Ok(candidates)
+}
+
+fn score_class(score: i32) -> Option<usize> {
+    usize::try_from(score).ok().filter(|&class| class < SCORE_CLASSES)
+}
+
+/// Every core the player was served in games before `game_id`, and whether
+/// they ever mis-scored it (timeouts aside).
+fn served_cores(connection: &mut PgConnection, user_id: &str, game_id: i32) -> Result<HashMap<i32, bool>, ApiError> {
+    let rows = challenges::table
+        .inner_join(games::table)
+        .filter(games::user_id.eq(user_id))
+        .filter(games::id.lt(game_id))
+        .filter(challenges::started_at.is_not_null())
+        .select((challenges::core_id, challenges::points, challenges::timed_out))
+        .load::<(i32, Option<i32>, bool)>(connection)?;
+
+    let mut served: HashMap<i32, bool> = HashMap::new();
+    for (core_id, points, timed_out) in rows {
+        let missed = points.is_some_and(|points| points < 0) && !timed_out;
+        *served.entry(core_id).or_default() |= missed;
+    }
+    Ok(served)
+}
+
+/// Shuffles the candidates and takes the first `remaining`, or with
+/// stratified sampling the first ones of each class up to its target, so
+/// classes stay interleaved in the shuffled order. With a rotation, the
+/// player's history decides the order the candidates are considered in (see
+/// `rotation_order`).
+fn draw_random(
+    mut candidates: Vec<(i32, i32)>,
+    sampling: Sampling,
+    total: i32,
+    remaining: usize,
+    initial_class: Option<usize>,
+    rotation: Option<(Rotation, &HashMap<i32, bool>)>,
+    rng: &mut ChaCha8Rng
+) -> Selection {
+    let mut available = [0; SCORE_CLASSES];
+    for class in candidates.iter().filter_map(|&(_, score)| score_class(score)) {
+        available[class] += 1;
+    }
+    let allocation = class_targets(sampling, total, available, initial_class);
+
+    let (ordered, reshow) = match rotation {
+        Some((rotation, history)) => rotation_order(candidates, history, remaining, rotation, rng),
+        None => {
+            candidates.shuffle(rng);
+            (candidates, 0)
+        }
+    };
+
+    let mut core_ids: Vec<i32> = match allocation {
+        None => ordered.iter().take(remaining).map(|&(core_id, _)| core_id).collect(),
+        Some(allocation) => {
+            let mut wanted = allocation.counts;
+            ordered
+                .iter()
+                .filter(|&&(_, score)| match score_class(score) {
+                    Some(class) if wanted[class] > 0 => {
+                        wanted[class] -= 1;
+                        true
+                    }
+                    _ => false,
+                })
+                .take(remaining)
+                .map(|&(core_id, _)| core_id)
+                .collect()
+        }
+    };
+
+    let mut selection = Selection {
+        topped_up: allocation.is_some_and(|allocation| allocation.topped_up),
+        ..Selection::default()
+    };
+    if let Some((_, history)) = rotation {
+        // The tiers would otherwise put re-shown cores first and recycled
+        // ones last.
+        core_ids.shuffle(rng);
+        let reshow_picks: Vec<i32> = ordered.iter().take(reshow).map(|&(core_id, _)| core_id).collect();
+        selection.reshown = core_ids.iter().filter(|core_id| reshow_picks.contains(core_id)).count();
+        selection.recycled = core_ids.iter().filter(|core_id| history.contains_key(core_id)).count() - selection.reshown;
+    }
+    selection.core_ids = core_ids;
+    selection
+}
+
+/// Orders candidates in three tiers, each shuffled: the previously missed
+/// cores picked for re-showing (`reshow_missed_rate` of the game, as far as
+/// there are any), then cores never served, then everything already served.
+/// Returns the order and how many re-show picks lead it.
+fn rotation_order(
+    candidates: Vec<(i32, i32)>,
+    history: &HashMap<i32, bool>,
+    remaining: usize,
+    rotation: Rotation,
+    rng: &mut ChaCha8Rng
+) -> (Vec<(i32, i32)>, usize) {
+    let (served, mut unseen): (Vec<_>, Vec<_>) = candidates
+        .into_iter()
+        .partition(|(core_id, _)| history.contains_key(core_id));
+    let (mut missed, mut recyclable): (Vec<_>, Vec<_>) = served
+        .into_iter()
+        .partition(|(core_id, _)| history.get(core_id).copied().unwrap_or(false));
+
+    missed.shuffle(rng);
+    let reshow = ((remaining as f64 * rotation.reshow_missed_rate).round() as usize).min(missed.len());
+    let mut ordered: Vec<(i32, i32)> = missed.drain(..reshow).collect();
+
+    unseen.shuffle(rng);
+    recyclable.append(&mut missed);
+    recyclable.shuffle(rng);
+    ordered.append(&mut unseen);
+    ordered.append(&mut recyclable);
+    (ordered, reshow)
+}
+
+/// Weighted sampling without replacement (Efraimidis-Spirakis): each core
+/// gets an exponential key with rate equal to its weight and the smallest
+/// keys win.
+fn draw_weighted(mistakes: Vec<Mistake>, total: i32, rng: &mut ChaCha8Rng) -> Vec<i32> {
+    let mut keyed: Vec<(f64, i32)> = mistakes
+        .into_iter()
+        .map(|mistake| (-(1.0 - rng.gen::<f64>()).ln() / mistake.weight.max(1e-9), mistake.core_id))
+        .collect();
+    keyed.sort_by(|a, b| a.0.total_cmp(&b.0));
+    keyed.into_iter().take(total.max(0) as usize).map(|(_, core_id)| core_id).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cores 1 to 40, ten of each score.
+    fn candidates() -> Vec<(i32, i32)> {
+        (1..=40).map(|core_id| (core_id, core_id % 4)).collect()
+    }
+
+    fn draw(sampling: Sampling, seed: i64) -> Selection {
+        draw_random(candidates(), sampling, 12, 12, None, None, &mut rng(seed))
+    }
+
+    fn mistakes() -> Vec<Mistake> {
+        (1..=20).map(|core_id| Mistake { core_id, weight: f64::from(core_id) }).collect()
+    }
+
+    #[test]
+    fn random_draw_is_reproducible_from_its_seed() {
+        for sampling in [Sampling::Uniform, Sampling::Balanced, Sampling::Explicit([6, 2, 2, 2])] {
+            let first = draw(sampling, 42);
+            assert_eq!(first.core_ids.len(), 12);
+            assert_eq!(first.core_ids, draw(sampling, 42).core_ids);
+            assert_ne!(first.core_ids, draw(sampling, 43).core_ids);
+        }
+    }
+
+    const PINNED_UNIFORM: [i32; 12] = [12, 9, 31, 32, 20, 29, 3, 1, 18, 36, 21, 16];
+    const PINNED_BALANCED: [i32; 12] = [12, 9, 31, 32, 20, 29, 3, 1, 18, 2, 39, 26];
+
+    #[test]
+    fn random_draw_is_stable_across_releases() {
+        // Stored seeds must keep selecting the same cores; if this fails after
+        // a dependency upgrade, the audit can no longer re-derive old games.
+        assert_eq!(draw(Sampling::Uniform, 42).core_ids, PINNED_UNIFORM);
+        assert_eq!(draw(Sampling::Balanced, 42).core_ids, PINNED_BALANCED);
+    }
+
+    #[test]
+    fn stratified_draw_keeps_its_targets() {
+        let selection = draw(Sampling::Explicit([6, 2, 2, 2]), 7);
+        let mut per_class = [0; SCORE_CLASSES];
+        for core_id in &selection.core_ids {
+            per_class[(core_id % 4) as usize] += 1;
+        }
+        assert_eq!(per_class, [6, 2, 2, 2]);
+        assert!(!selection.topped_up);
+    }
+
+    #[test]
+    fn initial_core_leaves_one_slot_fewer() {
+        let selection = draw_random(candidates(), Sampling::Balanced, 12, 11, Some(1), None, &mut rng(42));
+        let mut per_class = [0; SCORE_CLASSES];
+        for core_id in &selection.core_ids {
+            per_class[(core_id % 4) as usize] += 1;
+        }
+        assert_eq!(per_class, [3, 2, 3, 3]);
+    }
+
+    #[test]
+    fn weighted_draw_is_reproducible_from_its_seed() {
+        let first = draw_weighted(mistakes(), 5, &mut rng(42));
+        assert_eq!(first.len(), 5);
+        assert_eq!(first, draw_weighted(mistakes(), 5, &mut rng(42)));
+        assert_ne!(first, draw_weighted(mistakes(), 5, &mut rng(43)));
+        // Fewer mistakes than asked for gives all of them.
+        assert_eq!(draw_weighted(mistakes(), 50, &mut rng(42)).len(), 20);
+    }
+
+    /// Cores 1 to `served` served before, the first `missed` of them
+    /// mis-scored.
+    fn history(served: i32, missed: i32) -> HashMap<i32, bool> {
+        (1..=served).map(|core_id| (core_id, core_id <= missed)).collect()
+    }
+
+    fn rotate(history: &HashMap<i32, bool>, reshow_missed_rate: f64, seed: i64) -> Selection {
+        let rotation = Rotation { reshow_missed_rate };
+        draw_random(candidates(), Sampling::Uniform, 12, 12, None, Some((rotation, history)), &mut rng(seed))
+    }
+
+    #[test]
+    fn rotation_serves_unseen_cores_first() {
+        let history = history(20, 10);
+        for seed in 0..20 {
+            let selection = rotate(&history, 0.0, seed);
+            assert_eq!(selection.core_ids.len(), 12);
+            assert!(selection.core_ids.iter().all(|core_id| *core_id > 20), "seed {}: {:?}", seed, selection.core_ids);
+            assert_eq!((selection.reshown, selection.recycled), (0, 0));
+        }
+    }
+
+    #[test]
+    fn rotation_reshows_missed_cores_at_the_rate() {
+        let history = history(20, 10);
+        for seed in 0..20 {
+            // A quarter of 12 challenges.
+            let selection = rotate(&history, 0.25, seed);
+            let reshown: Vec<_> = selection.core_ids.iter().filter(|core_id| **core_id <= 10).collect();
+            assert_eq!(reshown.len(), 3, "seed {}: {:?}", seed, selection.core_ids);
+            assert!(selection.core_ids.iter().all(|core_id| *core_id <= 10 || *core_id > 20));
+            assert_eq!((selection.reshown, selection.recycled), (3, 0));
+        }
+    }
+
+    #[test]
+    fn rotation_reshows_no_more_than_were_missed() {
+        let selection = rotate(&history(20, 2), 0.5, 42);
+        assert_eq!(selection.core_ids.iter().filter(|core_id| **core_id <= 2).count(), 2);
+        assert_eq!((selection.reshown, selection.recycled), (2, 0));
+    }
+
+    #[test]
+    fn rotation_recycles_only_once_the_set_is_exhausted() {
+        let history = history(35, 0);
+        for seed in 0..20 {
+            let selection = rotate(&history, 0.0, seed);
+            for unseen in 36..=40 {
+                assert!(selection.core_ids.contains(&unseen), "seed {}: {:?}", seed, selection.core_ids);
+            }
+            assert_eq!((selection.reshown, selection.recycled), (0, 7));
+        }
+    }
+
+    #[test]
+    fn rotation_shuffles_reshown_cores_into_the_game() {
+        // Without the final shuffle the re-shown cores would always lead.
+        let history = history(20, 10);
+        let led = (0..20).filter(|&seed| rotate(&history, 0.25, seed).core_ids[..3].iter().all(|core_id| *core_id <= 10));
+        assert!(led.count() < 20);
+    }
+}
@@ -0,0 +1,36 @@
+use std::time::Duration;
+
+use axum_server::Handle;
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Resolves on the first SIGTERM (process supervisor) or SIGINT (Ctrl-C).
+pub async fn shutdown_signal() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(s) => s,
+        Err(e) => {
+            tracing::error!("Failed to install SIGTERM handler, only Ctrl-C will shut down gracefully: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+
+    tokio::select! {
+        _ = terminate.recv() => tracing::info!("Received SIGTERM"),
+        _ = tokio::signal::ctrl_c() => tracing::info!("Received SIGINT"),
+    }
+}
+
+/// On shutdown signal, stops `handle`'s server from accepting connections and
+/// gives in-flight requests up to `grace` to complete. The server future
+/// returns once they have drained (or the grace period has run out).
+pub fn spawn_graceful_shutdown(handle: Handle, grace: Duration) {
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        tracing::info!(
+            "Shutting down: no longer accepting connections, waiting up to {}s for {} in-flight connection(s)",
+            grace.as_secs(),
+            handle.connection_count()
+        );
+        handle.graceful_shutdown(Some(grace));
+    });
+}
@@ -0,0 +1,107 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use diesel::{
+    sql_query,
+    sql_types::{BigInt, Integer, Timestamptz, Varchar},
+    QueryableByName, RunQueryDsl
+};
+use tokio::time::MissedTickBehavior;
+use tracing::Instrument;
+
+use crate::{
+    config::SweeperConfig,
+    error::ApiError,
+    games::finalize_game,
+    models::{EndReason, GameMode},
+    run_db, PgPool
+};
+
+/// At most this many games are finalized per pass; any left over are picked
+/// up on the next one.
+const BATCH_SIZE: i64 = 500;
+
+/// Open games whose last activity (game start, or any challenge started or
+/// submitted) is older than the cutoff.
+const SQL_IDLE_GAMES: &str = r#"
+SELECT games.id, games.game_type, games.user_id, activity.last_activity
+FROM games
+CROSS JOIN LATERAL (
+    SELECT GREATEST(games.started_at, MAX(challenges.started_at), MAX(challenges.submitted_at)) AS last_activity
+    FROM challenges
+    WHERE challenges.game_id = games.id
+) AS activity
+WHERE games.finished_at IS NULL AND activity.last_activity < $1
+ORDER BY games.id
+LIMIT $2
+"#;
+
+#[derive(QueryableByName)]
+struct IdleGame {
+    #[diesel(sql_type = Integer)]
+    id: i32,
+    #[diesel(sql_type = Varchar)]
+    game_type: GameMode,
+    #[diesel(sql_type = Varchar)]
+    user_id: String,
+    #[diesel(sql_type = Timestamptz)]
+    last_activity: DateTime<Utc>,
+}
+
+/// Periodically finalizes games nobody has touched for the configured idle
+/// timeout, the same way `quit_game` does, with `end_reason = abandoned`.
+pub fn spawn_abandoned_game_sweeper(pool: PgPool, config: SweeperConfig) {
+    if !config.enabled {
+        return;
+    }
+    let idle_timeout = Duration::from_secs(config.idle_timeout_mins * 60);
+
+    tokio::spawn(
+        async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(config.interval_secs));
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                ticker.tick().await;
+                match sweep_abandoned_games(&pool, idle_timeout).await {
+                    Ok(0) => tracing::debug!("No abandoned games"),
+                    Ok(finalized) => tracing::info!("Finalized {} abandoned game(s)", finalized),
+                    Err(e) => tracing::warn!("Abandoned game sweep failed: {}", e),
+                }
+            }
+        }
+        .instrument(tracing::info_span!("abandoned_game_sweeper"))
+    );
+}
+
+/// One pass of the sweeper. Returns how many games were finalized.
+pub async fn sweep_abandoned_games(pool: &PgPool, idle_timeout: Duration) -> Result<usize, ApiError> {
+    let idle_timeout = chrono::Duration::from_std(idle_timeout)
+        .map_err(|e| ApiError::internal(format!("Idle timeout out of range: {}", e)))?;
+
+    run_db(pool, move |connection| {
+        let cutoff = Utc::now() - idle_timeout;
+        let idle_games = sql_query(SQL_IDLE_GAMES)
+            .bind::<Timestamptz, _>(cutoff)
+            .bind::<BigInt, _>(BATCH_SIZE)
+            .load::<IdleGame>(connection)?;
+
+        let mut finalized = 0;
+        for game in idle_games {
+            // The game is recorded as ending when the player was last seen,
+            // not when the sweeper happened to notice.
+            if finalize_game(connection, game.id, game.last_activity, EndReason::Abandoned)? {
+                tracing::info!(
+                    game_id = game.id,
+                    user_id = %game.user_id,
+                    "Finalized abandoned {} game, idle since {}",
+                    game.game_type,
+                    game.last_activity
+                );
+                metrics::counter!("biogames_games_abandoned_total", "game_type" => game.game_type.as_str()).increment(1);
+                finalized += 1;
+            }
+        }
+        Ok(finalized)
+    })
+    .await
+}
@@ -0,0 +1,145 @@
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{MatchedPath, Request},
+    http::Response,
+    middleware::Next
+};
+use metrics_exporter_prometheus::{BuildError, Matcher, PrometheusBuilder, PrometheusHandle};
+use tower_http::{
+    classify::{ServerErrorsAsFailures, SharedClassifier},
+    trace::{DefaultOnBodyChunk, DefaultOnEos, MakeSpan, OnResponse, TraceLayer}
+};
+use tracing::{field::Empty, Span};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+use crate::{
+    config::{LogFormat, LoggingConfig},
+    request_id::X_REQUEST_ID
+};
+
+/// Installs the global subscriber. `RUST_LOG`, when set, replaces the
+/// configured filter entirely.
+pub fn init_tracing(config: &LoggingConfig) {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(&config.filter));
+    let registry = tracing_subscriber::registry().with(filter);
+
+    match config.format {
+        LogFormat::Text => registry.with(tracing_subscriber::fmt::layer()).init(),
+        LogFormat::Json => registry
+            .with(tracing_subscriber::fmt::layer().json().with_current_span(true).with_span_list(false))
+            .init(),
+    }
+}
+
+pub type RequestTraceLayer = TraceLayer<
+    SharedClassifier<ServerErrorsAsFailures>,
+    RequestSpan,
+    (),
+    RequestSpan,
+    DefaultOnBodyChunk,
+    DefaultOnEos,
+    ()
+>;
+
+/// One span per request, carrying the fields needed to reconstruct what a
+/// participant saw: method, route template, request id, and, once a handler
+/// knows them, the user, game and challenge involved. Status and latency are
+/// filled in when the response is ready.
+///
+/// Failures are not logged here; `ApiError` already logs them with context.
+pub fn trace_layer() -> RequestTraceLayer {
+    TraceLayer::new_for_http()
+        .make_span_with(RequestSpan)
+        .on_request(())
+        .on_response(RequestSpan)
+        .on_failure(())
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RequestSpan;
+
+impl<B> MakeSpan<B> for RequestSpan {
+    fn make_span(&mut self, request: &Request<B>) -> Span {
+        let route = request.extensions()
+            .get::<MatchedPath>()
+            .map(|path| path.as_str())
+            .unwrap_or_else(|| request.uri().path());
+        let request_id = request.headers()
+            .get(&X_REQUEST_ID)
+            .and_then(|id| id.to_str().ok())
+            .unwrap_or_default();
+
+        tracing::info_span!(
+            "request",
+            method = %request.method(),
+            route = %route,
+            request_id = %request_id,
+            status = Empty,
+            latency_ms = Empty,
+            user_id = Empty,
+            game_id = Empty,
+            challenge_id = Empty,
+        )
+    }
+}
+
+impl<B> OnResponse<B> for RequestSpan {
+    fn on_response(self, response: &Response<B>, latency: Duration, span: &Span) {
+        span.record("status", response.status().as_u16());
+        span.record("latency_ms", latency.as_millis() as u64);
+        tracing::info!("request completed");
+    }
+}
+
+/// Attaches the participant's user id to the current request span.
+pub fn record_user_id(user_id: &str) {
+    Span::current().record("user_id", user_id);
+}
+
+/// Attaches a game id to the current request span.
+pub fn record_game_id(game_id: i32) {
+    Span::current().record("game_id", game_id);
+}
+
+/// Attaches a challenge id to the current request span.
+pub fn record_challenge_id(challenge_id: i32) {
+    Span::current().record("challenge_id", challenge_id);
+}
+
+/// Installs the global Prometheus recorder. Call once at startup; the handle
+/// renders the text exposition served at `/metrics`.
+pub fn install_metrics_recorder() -> Result<PrometheusHandle, BuildError> {
+    const LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+    // Every value `scoring::get_score` can return, so each gets its own bucket.
+    const POINTS_BUCKETS: &[f64] = &[-5.0, -4.0, -3.0, -2.0, -1.0, 5.0];
+
+    PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Suffix("_seconds".to_string()), LATENCY_BUCKETS)?
+        .set_buckets_for_metric(Matcher::Full("biogames_challenge_points".to_string()), POINTS_BUCKETS)?
+        .install_recorder()
+}
+
+/// Records `biogames_http_request_duration_seconds` per method, route
+/// template and status. Unmatched paths share one label so scanners can't
+/// blow up the series count.
+pub async fn track_http_metrics(request: Request, next: Next) -> axum::response::Response {
+    let start = Instant::now();
+    let method = request.method().to_string();
+    let route = request.extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+
+    let response = next.run(request).await;
+
+    metrics::histogram!(
+        "biogames_http_request_duration_seconds",
+        "method" => method,
+        "route" => route,
+        "status" => response.status().as_u16().to_string()
+    )
+    .record(start.elapsed().as_secs_f64());
+    response
+}
@@ -0,0 +1,130 @@
+use diesel::{
+    dsl::count_distinct,
+    insert_into,
+    prelude::*,
+    sql_query,
+    sql_types::{BigInt, Integer, Text}
+};
+
+use crate::{
+    error::ApiError,
+    models::TestForm,
+    protocol::{Phase, Protocol},
+    schema::form_assignments
+};
+
+/// Namespace for the advisory lock that numbers participants as they're
+/// assigned, see `lock_user_games`.
+const FORM_ASSIGNMENT_LOCK: i32 = 0x666f_726d; // "form"
+
+/// The form `user_id` takes in `phase`. The first time, the participant is
+/// given the next rotation and assigned a form for every form phase of the
+/// protocol at once, so the whole sequence is fixed from their first test.
+/// Must be called inside the game creation transaction.
+pub fn assigned_form<'a>(
+    connection: &mut PgConnection,
+    protocol: &'a Protocol,
+    user_id: &str,
+    phase: &Phase
+) -> Result<&'a TestForm, ApiError> {
+    let form_phases: Vec<&Phase> = protocol.phases.iter().filter(|phase| phase.uses_test_form).collect();
+    let form_phase_ids: Vec<i32> = form_phases.iter().map(|phase| phase.id).collect();
+
+    let assigned = form_assignments::table
+        .filter(form_assignments::user_id.eq(user_id))
+        .filter(form_assignments::phase_id.eq(phase.id))
+        .select(form_assignments::test_form_id)
+        .first::<i32>(connection)
+        .optional()?;
+    let form_id = match assigned {
+        Some(form_id) => form_id,
+        None => {
+            sql_query("SELECT pg_advisory_xact_lock($1, $2)")
+                .bind::<Integer, _>(FORM_ASSIGNMENT_LOCK)
+                .bind::<Integer, _>(protocol.id)
+                .execute(connection)?;
+
+            // A participant assigned before a form phase was added keeps
+            // their rotation.
+            let rotation = match form_assignments::table
+                .filter(form_assignments::user_id.eq(user_id))
+                .filter(form_assignments::phase_id.eq_any(&form_phase_ids))
+                .select(form_assignments::rotation)
+                .first::<i32>(connection)
+                .optional()?
+            {
+                Some(rotation) => rotation,
+                None => {
+                    let assigned_users = form_assignments::table
+                        .filter(form_assignments::phase_id.eq_any(&form_phase_ids))
+                        .select(count_distinct(form_assignments::user_id))
+                        .get_result::<i64>(connection)?;
+                    (assigned_users % protocol.forms.len() as i64) as i32
+                }
+            };
+
+            let rows: Vec<_> = form_phases
+                .iter()
+                .enumerate()
+                .map(|(k, form_phase)| {
+                    let form = &protocol.forms[(rotation as usize + k) % protocol.forms.len()];
+                    (
+                        form_assignments::user_id.eq(user_id),
+                        form_assignments::phase_id.eq(form_phase.id),
+                        form_assignments::test_form_id.eq(form.id),
+                        form_assignments::rotation.eq(rotation)
+                    )
+                })
+                .collect();
+            insert_into(form_assignments::table)
+                .values(&rows)
+                .on_conflict_do_nothing()
+                .execute(connection)?;
+            tracing::info!("Assigned test form rotation {} to user {}", rotation, user_id);
+
+            form_assignments::table
+                .filter(form_assignments::user_id.eq(user_id))
+                .filter(form_assignments::phase_id.eq(phase.id))
+                .select(form_assignments::test_form_id)
+                .first::<i32>(connection)?
+        }
+    };
+
+    protocol.forms
+        .iter()
+        .find(|form| form.id == form_id)
+        .ok_or_else(|| ApiError::internal(format!(
+            "User {} is assigned test form {}, which is not in protocol {}",
+            user_id, form_id, protocol.name
+        )))
+}
+
+/// Two forms of the same protocol sharing cores, which defeats the point of
+/// parallel forms.
+#[derive(Debug, QueryableByName)]
+pub struct FormOverlap {
+    #[diesel(sql_type = Text)]
+    pub protocol: String,
+    #[diesel(sql_type = Text)]
+    pub form_a: String,
+    #[diesel(sql_type = Text)]
+    pub form_b: String,
+    #[diesel(sql_type = BigInt)]
+    pub shared_cores: i64,
+}
+
+pub fn overlapping_forms(connection: &mut PgConnection) -> Result<Vec<FormOverlap>, ApiError> {
+    Ok(sql_query(r#"
+        SELECT study_protocols.name AS protocol, a.name AS form_a, b.name AS form_b, COUNT(*) AS shared_cores
+        FROM test_forms a
+        JOIN test_forms b ON b.protocol_id = a.protocol_id AND b.position > a.position
+        JOIN core_sets set_a ON set_a.name = a.core_set
+        JOIN core_sets set_b ON set_b.name = b.core_set
+        JOIN core_set_members member_a ON member_a.core_set_id = set_a.id
+        JOIN core_set_members member_b ON member_b.core_set_id = set_b.id AND member_b.core_id = member_a.core_id
+        JOIN study_protocols ON study_protocols.id = a.protocol_id
+        GROUP BY study_protocols.name, a.name, b.name
+        ORDER BY study_protocols.name, a.name, b.name
+        "#)
+        .load(connection)?)
+}
@@ -0,0 +1,104 @@
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+use axum::{
+    http::{header::HOST, uri::{Authority, Scheme}, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Redirect},
+    Router
+};
+use axum_server::tls_rustls::RustlsConfig;
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::config::TlsConfig;
+
+pub async fn load_rustls_config(tls: &TlsConfig) -> std::io::Result<RustlsConfig> {
+    RustlsConfig::from_pem_file(&tls.cert_path, &tls.key_path).await
+}
+
+/// Keeps the served certificate in sync with the files on disk. A reload is
+/// triggered by SIGHUP or, when polling is enabled, by a change in either
+/// file's modification time. A failed reload keeps the previous certificate.
+pub fn spawn_certificate_reloader(rustls: RustlsConfig, tls: TlsConfig) {
+    tokio::spawn(async move {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::error!("Failed to install SIGHUP handler, certificate reload on signal disabled: {}", e);
+                return;
+            }
+        };
+
+        let poll_every = (tls.reload_poll_secs > 0).then(|| Duration::from_secs(tls.reload_poll_secs));
+        let mut last_modified = certificate_mtimes(&tls).await;
+
+        loop {
+            let reason = tokio::select! {
+                _ = hangup.recv() => "SIGHUP",
+                _ = sleep_or_forever(poll_every) => {
+                    let modified = certificate_mtimes(&tls).await;
+                    if modified == last_modified {
+                        continue;
+                    }
+                    last_modified = modified;
+                    "certificate files changed"
+                }
+            };
+
+            match rustls.reload_from_pem_file(&tls.cert_path, &tls.key_path).await {
+                Ok(()) => tracing::info!("Reloaded TLS certificate ({})", reason),
+                Err(e) => tracing::error!("Failed to reload TLS certificate ({}), keeping the previous one: {}", reason, e),
+            }
+        }
+    });
+}
+
+async fn sleep_or_forever(duration: Option<Duration>) {
+    match duration {
+        Some(d) => tokio::time::sleep(d).await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn certificate_mtimes(tls: &TlsConfig) -> (Option<SystemTime>, Option<SystemTime>) {
+    async fn mtime(path: &std::path::Path) -> Option<SystemTime> {
+        tokio::fs::metadata(path).await.ok()?.modified().ok()
+    }
+    (mtime(&tls.cert_path).await, mtime(&tls.key_path).await)
+}
+
+/// Plain HTTP listener that sends every request to the HTTPS port on the same
+/// host, preserving path and query.
+pub async fn serve_http_redirect(addr: SocketAddr, https_port: u16) {
+    let redirect = move |headers: HeaderMap, uri: Uri| async move {
+        match https_uri(&headers, uri, https_port) {
+            Some(target) => Redirect::permanent(&target.to_string()).into_response(),
+            None => (StatusCode::BAD_REQUEST, "Missing or invalid Host header").into_response(),
+        }
+    };
+    let app = Router::new().fallback(redirect);
+
+    tracing::debug!("redirecting HTTP on {} to HTTPS port {}", addr, https_port);
+    if let Err(e) = axum_server::bind(addr)
+        .serve(app.into_make_service())
+        .await
+    {
+        tracing::error!("HTTP redirect listener on {} failed: {}", addr, e);
+    }
+}
+
+fn https_uri(headers: &HeaderMap, uri: Uri, https_port: u16) -> Option<Uri> {
+    let host = headers.get(HOST)?.to_str().ok()?.parse::<Authority>().ok()?;
+    let authority = if https_port == 443 {
+        host.host().to_string()
+    } else {
+        format!("{}:{}", host.host(), https_port)
+    };
+
+    let mut parts = uri.into_parts();
+    parts.scheme = Some(Scheme::HTTPS);
+    parts.authority = Some(authority.parse().ok()?);
+    if parts.path_and_query.is_none() {
+        parts.path_and_query = Some("/".parse().ok()?);
+    }
+    Uri::from_parts(parts).ok()
+}
@@ -35,6 +35,7 @@ app.use(compression());
 app.use('/proxy-api', createProxyMiddleware({
   target: 'http://164.67.195.107:3001',
   changeOrigin: true, // Recommended for virtual hosted sites & to change host header to target
+  xfwd: true, // Send X-Forwarded-For so the API rate limits per participant
   pathRewrite: {
     '^/proxy-api': '', // Remove /proxy-api from the start of the path
                      // e.g., /proxy-api/api/preview_core_id -> /api/preview_core_id
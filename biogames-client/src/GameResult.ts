@@ -2,5 +2,6 @@ export default interface GameResult {
     challenge_id: number,
     guess: number,
     correct_score: number,
-    points: number
+    points: number,
+    timed_out: boolean
 }
@@ -4,5 +4,6 @@ export default interface GameResults {
     severe_mistakes: GameResult[],
     moderate_mistakes: GameResult[],
     mild_mistakes: GameResult[],
-    correct: GameResult[]
+    correct: GameResult[],
+    timed_out: GameResult[]
 }
@@ -39,8 +39,7 @@ function IntroductionPage() {
 
       if (!response.ok) {
         console.log(response);
-        const errorData = await response.json();
-        throw new Error(errorData.message || 'Failed to register');
+        throw new Error(data.message || 'Failed to register');
       }
 
       if (!data.success) {
@@ -57,6 +57,10 @@ function ResultsPage({ mode }: ResultsPageProps) {
                         className="text-[green]"
                         results={gameQuery.data.results.correct}
                         title="Correct"/>
+                    <ResultsDisplay
+                        className="text-[gray]"
+                        results={gameQuery.data.results.timed_out}
+                        title="Timed Out"/>
                 </div>
             </div>
             <button className="p-2 mt-2 bg-primary-500 text-white" onClick={playAgain}>Play Again</button>
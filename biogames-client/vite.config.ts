@@ -13,6 +13,7 @@ export default defineConfig({
       '/proxy-api': {
         target: 'http://localhost:3001',
         changeOrigin: true,
+        xfwd: true,
         secure: false,
         rewrite: (path) => path.replace(/^\/proxy-api/, '')
       }